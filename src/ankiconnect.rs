@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{config::Connection, error::AnkiError};
+
+#[derive(Serialize)]
+struct RB<'a> {
+    action: &'a str,
+    version: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    params: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Response<T> {
+    error: Option<serde_json::Value>,
+    result: Option<T>,
+}
+
+impl<T> Response<T> {
+    pub fn into_result(self) -> Result<Option<T>, AnkiError> {
+        match self.error {
+            None | Some(serde_json::Value::Null) => Ok(self.result),
+            Some(serde_json::Value::String(e)) => Err(AnkiError::Anki(e)),
+            Some(e) => Err(AnkiError::Anki(e.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Field {
+    pub value: String,
+    pub order: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Note<F> {
+    pub deck_name: String,
+    pub model_name: String,
+    pub fields: F,
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<NoteOptions>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NoteOptions {
+    pub allow_duplicate: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duplicate_scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duplicate_scope_options: Option<DuplicateScopeOptions>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DuplicateScopeOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deck_name: Option<String>,
+    pub check_children: bool,
+    pub check_all_models: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NoteInfo {
+    pub note_id: i64,
+    pub model_name: String,
+    pub tags: Vec<String>,
+    pub fields: HashMap<String, Field>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaSource {
+    Data(String),
+    Path(String),
+    Url(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct Action {
+    pub action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub params: Option<serde_json::Value>,
+    /// Version 6 and later wrap each result with its own error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    conn: Connection,
+}
+
+impl Client {
+    pub fn new(conn: Connection) -> Self {
+        Self { conn }
+    }
+
+    pub fn request<T: DeserializeOwned>(
+        &self,
+        action: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<Option<T>, AnkiError> {
+        let body = RB {
+            action,
+            version: self.conn.version,
+            key: self.conn.api_key.as_deref(),
+            params,
+        };
+
+        let text = ureq::post(&self.conn.url)
+            .send_json(body)?
+            .into_string()
+            .map_err(|e| AnkiError::Connection(e.to_string()))?;
+        let data: Response<T> =
+            serde_json::from_str(&text).map_err(|e| AnkiError::deserialize(&e))?;
+
+        data.into_result()
+    }
+
+    fn request_some<T: DeserializeOwned>(
+        &self,
+        action: &str,
+        params: Option<serde_json::Value>,
+    ) -> Result<T, AnkiError> {
+        self.request(action, params)?
+            .ok_or_else(|| AnkiError::Anki(format!("{action} returned no result")))
+    }
+
+    /// Returns `None` when no card is being reviewed.
+    pub fn gui_current_card<T: DeserializeOwned>(&self) -> Result<Option<T>, AnkiError> {
+        self.request("guiCurrentCard", None)
+    }
+
+    pub fn gui_add_cards<F: Serialize>(&self, note: &Note<F>) -> Result<i64, AnkiError> {
+        self.request_some("guiAddCards", Some(serde_json::json!({ "note": note })))
+    }
+
+    pub fn add_note<F: Serialize>(&self, note: &Note<F>) -> Result<i64, AnkiError> {
+        self.request_some("addNote", Some(serde_json::json!({ "note": note })))
+    }
+
+    /// Returns the new note IDs, or `None` for the notes that could not be added.
+    pub fn add_notes<F: Serialize>(
+        &self,
+        notes: &[Note<F>],
+    ) -> Result<Vec<Option<i64>>, AnkiError> {
+        self.request_some("addNotes", Some(serde_json::json!({ "notes": notes })))
+    }
+
+    pub fn can_add_notes<F: Serialize>(&self, notes: &[Note<F>]) -> Result<Vec<bool>, AnkiError> {
+        self.request_some("canAddNotes", Some(serde_json::json!({ "notes": notes })))
+    }
+
+    pub fn find_notes(&self, query: &str) -> Result<Vec<i64>, AnkiError> {
+        self.request_some("findNotes", Some(serde_json::json!({ "query": query })))
+    }
+
+    pub fn notes_info(&self, notes: &[i64]) -> Result<Vec<NoteInfo>, AnkiError> {
+        self.request_some("notesInfo", Some(serde_json::json!({ "notes": notes })))
+    }
+
+    pub fn update_note_fields<F: Serialize>(&self, id: i64, fields: &F) -> Result<(), AnkiError> {
+        self.request::<serde_json::Value>(
+            "updateNoteFields",
+            Some(serde_json::json!({ "note": { "id": id, "fields": fields } })),
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_notes(&self, notes: &[i64]) -> Result<(), AnkiError> {
+        self.request::<serde_json::Value>(
+            "deleteNotes",
+            Some(serde_json::json!({ "notes": notes })),
+        )?;
+        Ok(())
+    }
+
+    pub fn gui_browse(&self, query: &str) -> Result<Vec<i64>, AnkiError> {
+        self.request_some("guiBrowse", Some(serde_json::json!({ "query": query })))
+    }
+
+    pub fn gui_edit_note(&self, note: i64) -> Result<(), AnkiError> {
+        self.request::<serde_json::Value>(
+            "guiEditNote",
+            Some(serde_json::json!({ "note": note })),
+        )?;
+        Ok(())
+    }
+
+    pub fn deck_names(&self) -> Result<Vec<String>, AnkiError> {
+        self.request_some("deckNames", None)
+    }
+
+    pub fn model_names(&self) -> Result<Vec<String>, AnkiError> {
+        self.request_some("modelNames", None)
+    }
+
+    pub fn model_field_names(&self, model_name: &str) -> Result<Vec<String>, AnkiError> {
+        self.request_some(
+            "modelFieldNames",
+            Some(serde_json::json!({ "modelName": model_name })),
+        )
+    }
+
+    /// Returns the filename Anki stored the media under.
+    pub fn store_media_file(
+        &self,
+        filename: &str,
+        source: &MediaSource,
+    ) -> Result<String, AnkiError> {
+        let params = match source {
+            MediaSource::Data(data) => serde_json::json!({ "filename": filename, "data": data }),
+            MediaSource::Path(path) => serde_json::json!({ "filename": filename, "path": path }),
+            MediaSource::Url(url) => serde_json::json!({ "filename": filename, "url": url }),
+        };
+        self.request_some("storeMediaFile", Some(params))
+    }
+
+    /// Returns the base64 encoded contents, or `None` if the file does not exist.
+    pub fn retrieve_media_file(&self, filename: &str) -> Result<Option<String>, AnkiError> {
+        let res: serde_json::Value = self.request_some(
+            "retrieveMediaFile",
+            Some(serde_json::json!({ "filename": filename })),
+        )?;
+        Ok(match res {
+            serde_json::Value::String(data) => Some(data),
+            _ => None,
+        })
+    }
+
+    /// An action for [`Client::multi`], of the version the client speaks.
+    pub fn action(&self, action: &str, params: Option<serde_json::Value>) -> Action {
+        Action {
+            action: action.to_owned(),
+            params,
+            version: Some(self.conn.version),
+        }
+    }
+
+    pub fn multi(
+        &self,
+        actions: &[Action],
+    ) -> Result<Vec<Result<Option<serde_json::Value>, AnkiError>>, AnkiError> {
+        let res: Vec<serde_json::Value> =
+            self.request_some("multi", Some(serde_json::json!({ "actions": actions })))?;
+        Ok(res
+            .into_iter()
+            .map(|r| match r {
+                // actions of version 6 and later are wrapped in their own envelope
+                serde_json::Value::Object(ref o)
+                    if o.len() == 2 && o.contains_key("result") && o.contains_key("error") =>
+                {
+                    serde_json::from_value::<Response<serde_json::Value>>(r)
+                        .map_err(|e| AnkiError::deserialize(&e))?
+                        .into_result()
+                }
+                r => Ok(Some(r)),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_into_result() {
+        let ok: Response<i64> = serde_json::from_str(r#"{"result": 1, "error": null}"#).unwrap();
+        assert_eq!(ok.into_result(), Ok(Some(1)));
+
+        let none: Response<i64> =
+            serde_json::from_str(r#"{"result": null, "error": null}"#).unwrap();
+        assert_eq!(none.into_result(), Ok(None));
+
+        let err: Response<i64> =
+            serde_json::from_str(r#"{"result": null, "error": "deck was not found"}"#).unwrap();
+        assert_eq!(
+            err.into_result(),
+            Err(AnkiError::Anki("deck was not found".into()))
+        );
+    }
+
+    #[test]
+    fn test_note_serialization() {
+        let note = Note {
+            deck_name: "Immersion".into(),
+            model_name: "Immersion".into(),
+            fields: HashMap::from([("Front", "噛[か]む")]),
+            tags: vec!["Immersion".into()],
+            options: Some(NoteOptions {
+                allow_duplicate: false,
+                duplicate_scope: Some("deck".into()),
+                duplicate_scope_options: None,
+            }),
+        };
+        assert_eq!(
+            serde_json::to_value(&note).unwrap(),
+            serde_json::json!({
+                "deckName": "Immersion",
+                "modelName": "Immersion",
+                "fields": { "Front": "噛[か]む" },
+                "tags": ["Immersion"],
+                "options": { "allowDuplicate": false, "duplicateScope": "deck" },
+            })
+        );
+    }
+}
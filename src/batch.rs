@@ -0,0 +1,320 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::{
+    ankiconnect::{Client, Note},
+    card::{derive_audio_guide, GuiAddCardsFields},
+    config::{Settings, Target},
+    error::AnkiError,
+    pitch, tts,
+};
+
+/// The rows of a CSV or TSV file, whose first line is the header.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Table {
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    /// `.tsv` and `.txt` files are tab separated, as are others whose first line has a tab.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        let tab = matches!(ext.as_deref(), Some("tsv" | "txt"))
+            || text.lines().next().is_some_and(|l| l.contains('\t'));
+        Self::parse(&text, if tab { b'\t' } else { b',' })
+    }
+
+    pub fn parse(text: &str, delimiter: u8) -> anyhow::Result<Self> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .flexible(true)
+            .from_reader(text.trim_start_matches('\u{feff}').as_bytes());
+        let headers = reader
+            .headers()?
+            .iter()
+            .map(|h| h.trim().to_owned())
+            .collect();
+        let rows = reader
+            .records()
+            .map(|r| Ok(r?.iter().map(String::from).collect()))
+            .collect::<anyhow::Result<Vec<Vec<String>>>>()?;
+        Ok(Self {
+            headers,
+            rows: rows
+                .into_iter()
+                .filter(|r| r.iter().any(|v| !v.trim().is_empty()))
+                .collect(),
+        })
+    }
+}
+
+/// The column each target field is read from, keyed on the target field name.
+pub type ColumnMapping = BTreeMap<String, usize>;
+
+/// Maps the target fields to the columns with the same name, ignoring case.
+pub fn default_mapping(headers: &[String], fields: &[String]) -> ColumnMapping {
+    fields
+        .iter()
+        .filter_map(|f| {
+            let col = headers.iter().position(|h| h.eq_ignore_ascii_case(f))?;
+            Some((f.clone(), col))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RowStatus {
+    Ready,
+    Invalid(String),
+    /// Has the same front as the given earlier row of the file.
+    DuplicateInFile(usize),
+    /// Anki would not add it, usually because the collection already has it.
+    Duplicate,
+    Added(i64),
+    Failed(String),
+}
+
+impl RowStatus {
+    pub fn is_duplicate(&self) -> bool {
+        matches!(self, Self::Duplicate | Self::DuplicateInFile(_))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchRow {
+    pub card: GuiAddCardsFields,
+    pub status: RowStatus,
+    /// Whether to submit the row, which duplicates are not by default.
+    pub include: bool,
+}
+
+impl BatchRow {
+    fn set_status(&mut self, status: RowStatus) {
+        self.include = status == RowStatus::Ready;
+        self.status = status;
+    }
+
+    pub fn can_submit(&self) -> bool {
+        self.include && !matches!(self.status, RowStatus::Invalid(_) | RowStatus::Added(_))
+    }
+}
+
+/// Builds a card for every row, filling a blank audio guide from the front,
+/// and flags the rows with a blank front or the same front as an earlier row.
+pub fn build_rows(
+    table: &Table,
+    mapping: &ColumnMapping,
+    target: &Target,
+    fields: &[String],
+) -> Vec<BatchRow> {
+    let mut fronts: BTreeMap<String, usize> = BTreeMap::new();
+    table
+        .rows
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let mut card = GuiAddCardsFields::default();
+            for field in fields {
+                let value = mapping
+                    .get(field)
+                    .and_then(|&col| row.get(col))
+                    .map_or("", |v| v.trim());
+                card.set(field, value.replace("\r\n", "\n").replace('\n', "<br />"));
+            }
+            let front = card.get(&target.front_field).to_owned();
+            if fields.contains(&target.audio_guide_field)
+                && card.get(&target.audio_guide_field).is_empty()
+            {
+                let audio_guide = derive_audio_guide(&front, target.audio_guide_style);
+                card.set(&target.audio_guide_field, audio_guide);
+            }
+
+            let status = if front.is_empty() {
+                RowStatus::Invalid(format!("{} is empty", target.front_field))
+            } else if let Some(&first) = fronts.get(&front) {
+                RowStatus::DuplicateInFile(first)
+            } else {
+                fronts.insert(front, i);
+                RowStatus::Ready
+            };
+            BatchRow {
+                card,
+                include: status == RowStatus::Ready,
+                status,
+            }
+        })
+        .collect()
+}
+
+fn note<'a>(
+    settings: &Settings,
+    card: &'a GuiAddCardsFields,
+    allow_duplicate: bool,
+) -> Note<&'a GuiAddCardsFields> {
+    let target = &settings.target;
+    let mut options = settings.add.note_options(&target.deck_name);
+    options.allow_duplicate = allow_duplicate;
+    Note {
+        deck_name: target.deck_name.clone(),
+        model_name: target.model_name.clone(),
+        fields: card,
+        tags: target.tags(None),
+        options: Some(options),
+    }
+}
+
+/// Flags the ready rows Anki would refuse with `canAddNotes` as duplicates, even
+/// when duplicates are allowed, which only applies once they are submitted.
+pub fn check_duplicates(
+    client: &Client,
+    settings: &Settings,
+    rows: &mut [BatchRow],
+) -> Result<(), AnkiError> {
+    let ready: Vec<usize> = (0..rows.len())
+        .filter(|&i| rows[i].status == RowStatus::Ready)
+        .collect();
+    if ready.is_empty() {
+        return Ok(());
+    }
+    let notes: Vec<_> = ready
+        .iter()
+        .map(|&i| note(settings, &rows[i].card, false))
+        .collect();
+    let can_add = client.can_add_notes(&notes)?;
+    for (i, ok) in ready.into_iter().zip(can_add) {
+        if !ok {
+            rows[i].set_status(RowStatus::Duplicate);
+        }
+    }
+    Ok(())
+}
+
+/// Fills the pitch accent and voices the audio of a row, as configured.
+fn annotate(
+    client: &Client,
+    settings: &Settings,
+    card: &mut GuiAddCardsFields,
+) -> Result<(), AnkiError> {
+    if settings.pitch.enabled {
+        pitch::fill_accent(&settings.pitch, &settings.target.front_field, card)?;
+    }
+    if settings.tts.enabled {
+        tts::fill_audio(
+            client,
+            &settings.tts,
+            &settings.target.audio_guide_field,
+            card,
+        )?;
+    }
+    Ok(())
+}
+
+/// Adds the included rows with an `addNote` each, duplicates among them included,
+/// and records in each row its note ID or why it was not added.
+pub fn submit(client: &Client, settings: &Settings, rows: &mut [BatchRow]) {
+    let mut picked: Vec<usize> = (0..rows.len()).filter(|&i| rows[i].can_submit()).collect();
+    picked.retain(|&i| {
+        let res = annotate(client, settings, &mut rows[i].card);
+        if let Err(e) = &res {
+            rows[i].set_status(RowStatus::Failed(e.to_string()));
+        }
+        res.is_ok()
+    });
+    if picked.is_empty() {
+        return;
+    }
+    // addNotes fails as a whole when any note fails, so each note is added on its own
+    let actions: Vec<_> = picked
+        .iter()
+        .map(|&i| {
+            let allow_duplicate = settings.add.allow_duplicate || rows[i].status.is_duplicate();
+            let note = note(settings, &rows[i].card, allow_duplicate);
+            client.action("addNote", Some(serde_json::json!({ "note": note })))
+        })
+        .collect();
+    let results = match client.multi(&actions) {
+        Ok(results) => results,
+        Err(e) => {
+            for &i in &picked {
+                rows[i].set_status(RowStatus::Failed(e.to_string()));
+            }
+            return;
+        }
+    };
+    for (n, &i) in picked.iter().enumerate() {
+        rows[i].set_status(match results.get(n) {
+            Some(Ok(Some(id))) if id.is_i64() => RowStatus::Added(id.as_i64().unwrap()),
+            Some(Err(e)) => RowStatus::Failed(e.to_string()),
+            _ => RowStatus::Failed("not added".into()),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Vec<String> {
+        crate::card::DEFAULT_TARGET_FIELDS
+            .map(String::from)
+            .to_vec()
+    }
+
+    #[test]
+    fn test_parse() {
+        let t = Table::parse(
+            "\u{feff}Front,Back\n\"噛[か]む\",\"to bite, to chew\"\n,\n殺[ころ]す,\"to \"\"kill\"\"\"\n",
+            b',',
+        )
+        .unwrap();
+        assert_eq!(t.headers, vec!["Front", "Back"]);
+        assert_eq!(
+            t.rows,
+            vec![
+                vec!["噛[か]む", "to bite, to chew"],
+                vec!["殺[ころ]す", "to \"kill\""],
+            ]
+        );
+
+        let t = Table::parse("Front\tBack\n噛[か]む\tto bite\n", b'\t').unwrap();
+        assert_eq!(t.rows, vec![vec!["噛[か]む", "to bite"]]);
+    }
+
+    #[test]
+    fn test_default_mapping() {
+        let headers = vec!["front".into(), "Notes".into(), "BACK".into()];
+        assert_eq!(
+            default_mapping(&headers, &fields()),
+            ColumnMapping::from([("Front".into(), 0), ("Back".into(), 2)])
+        );
+    }
+
+    #[test]
+    fn test_build_rows() {
+        let table = Table {
+            headers: vec!["Front".into(), "Back".into(), "AudioGuide".into()],
+            rows: vec![
+                vec!["噛[か]み 殺[ころ]す".into(), "to stifle\na yawn".into()],
+                vec!["".into(), "orphan".into()],
+                vec!["噛[か]み 殺[ころ]す".into(), "again".into()],
+                vec!["噛[か]む".into(), "".into(), "かむ".into()],
+            ],
+        };
+        let mapping = default_mapping(&table.headers, &fields());
+        let rows = build_rows(&table, &mapping, &Target::default(), &fields());
+
+        assert_eq!(rows[0].status, RowStatus::Ready);
+        assert!(rows[0].include);
+        assert_eq!(rows[0].card.get("Back"), "to stifle<br />a yawn");
+        assert_eq!(rows[0].card.get("AudioGuide"), "噛み殺す");
+        assert_eq!(rows[0].card.get("Audio"), "");
+        assert_eq!(rows[1].status, RowStatus::Invalid("Front is empty".into()));
+        assert_eq!(rows[2].status, RowStatus::DuplicateInFile(0));
+        assert!(!rows[2].include);
+        assert_eq!(rows[3].card.get("AudioGuide"), "かむ");
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ankiconnect::Field,
+    config::AudioGuideStyle,
+    error::AnkiError,
+    furigana, kana,
+    sanitize::Policy,
+    template::{self, TemplateError},
+};
+
+/// The fields of the default `Immersion` note type, used until the real ones are fetched.
+pub const DEFAULT_TARGET_FIELDS: [&str; 5] =
+    ["Front", "Back", "Back Paragraph", "AudioGuide", "Audio"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceCard {
+    pub deck_name: String,
+    pub model_name: String,
+    pub fields: HashMap<String, Field>,
+}
+
+impl SourceCard {
+    pub fn field_names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self.fields.iter().collect();
+        names.sort_by_key(|(_, f)| f.order);
+        names.into_iter().map(|(n, _)| n.as_str()).collect()
+    }
+}
+
+/// Field values of the note to create, keyed on the target note type's field names.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct GuiAddCardsFields(pub BTreeMap<String, String>);
+
+impl GuiAddCardsFields {
+    pub fn get(&self, name: &str) -> &str {
+        self.0.get(name).map_or("", String::as_str)
+    }
+
+    pub fn set(&mut self, name: &str, value: String) {
+        self.0.insert(name.to_owned(), value);
+    }
+}
+
+/// Templates composing each target field from the source card's fields,
+/// keyed on the target field name. See [`crate::template`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MappingProfile {
+    pub templates: BTreeMap<String, String>,
+    /// How the source fields are cleaned before the templates see them, keyed on
+    /// the source field name. Fields not listed are passed through.
+    pub policies: BTreeMap<String, Policy>,
+}
+
+impl Default for MappingProfile {
+    fn default() -> Self {
+        let templates = [
+            ("Front", "{{Kanji|reading:Kana}}"),
+            ("Back", "{{Meaning}}"),
+            (
+                "Back Paragraph",
+                "{{SentenceBack|trim|nl2br}}{{#Picture}}<br />{{Picture}}{{/Picture}}",
+            ),
+            ("AudioGuide", "{{Kanji}}"),
+            ("Audio", "{{KankenAudio}}"),
+        ];
+        let policies = [
+            ("SentenceBack", Policy::KeepRuby),
+            ("Picture", Policy::KeepMedia),
+            ("Meaning", Policy::KeepFormatting),
+        ];
+        Self {
+            templates: templates
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                .collect(),
+            policies: policies
+                .into_iter()
+                .map(|(k, v)| (k.to_owned(), v))
+                .collect(),
+        }
+    }
+}
+
+impl MappingProfile {
+    pub fn render(&self, target: &str, source: &SourceCard) -> Result<String, TemplateError> {
+        let Some(src) = self.templates.get(target) else {
+            return Ok(String::new());
+        };
+        let cleaned: HashMap<&str, String> = source
+            .fields
+            .iter()
+            .map(|(name, f)| {
+                let policy = self.policies.get(name).copied().unwrap_or_default();
+                (name.as_str(), policy.clean(&f.value))
+            })
+            .collect();
+        template::render(src, |name| cleaned.get(name).map(String::as_str))
+    }
+}
+
+/// Builds the target fields, in order of precedence, from the non-blank `overrides`,
+/// the `prev` card being maintained, or else the `source` card through the mapping
+/// profile for its note type.
+///
+/// Overrides are trimmed and their line breaks turned into `<br />`, and only apply
+/// to the target `fields`.
+pub fn compose_card(
+    overrides: &BTreeMap<String, String>,
+    prev: Option<&GuiAddCardsFields>,
+    source: Option<&SourceCard>,
+    profiles: &BTreeMap<String, MappingProfile>,
+    fields: &[String],
+) -> Result<GuiAddCardsFields, AnkiError> {
+    let overrides: BTreeMap<_, _> = overrides
+        .iter()
+        .filter(|(k, _)| fields.contains(k))
+        .map(|(k, v)| (k, v.trim().replace("\r\n", "\n").replace('\n', "<br />")))
+        .filter(|(_, v)| !v.is_empty())
+        .collect();
+
+    let mut card = match (prev, source) {
+        (Some(prev), _) => prev.clone(),
+        (None, Some(source)) => {
+            let profile = profiles
+                .get(&source.model_name)
+                .cloned()
+                .unwrap_or_default();
+            let mut card = GuiAddCardsFields::default();
+            for field in fields.iter().filter(|f| !overrides.contains_key(f)) {
+                let value = profile
+                    .render(field, source)
+                    .map_err(|e| AnkiError::Template {
+                        field: field.clone(),
+                        message: e.to_string(),
+                    })?;
+                card.set(field, value);
+            }
+            card
+        }
+        (None, None) => {
+            GuiAddCardsFields(fields.iter().map(|f| (f.clone(), String::new())).collect())
+        }
+    };
+    for (field, value) in overrides {
+        card.set(field, value);
+    }
+    Ok(card)
+}
+
+pub fn create_audio_guide(s: &str) -> String {
+    furigana::audio_guide(&furigana::parse(s))
+}
+
+/// The audio guide for a front in Anki's furigana syntax.
+pub fn derive_audio_guide(front: &str, style: AudioGuideStyle) -> String {
+    let tokens = furigana::parse(front);
+    match style {
+        AudioGuideStyle::Kanji => furigana::audio_guide(&tokens),
+        AudioGuideStyle::Hiragana => kana::to_hiragana(&furigana::reading_guide(&tokens)),
+        AudioGuideStyle::Katakana => kana::to_katakana(&furigana::reading_guide(&tokens)),
+        AudioGuideStyle::Romaji(system) => {
+            kana::to_romaji(&furigana::reading_guide(&tokens), system)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_audio_guide() {
+        assert_eq!(
+            create_audio_guide("ab[]c[de]f gh[ij]k (lmn) {op}"),
+            "abcfghklmnop".to_string()
+        );
+    }
+
+    #[test]
+    fn test_derive_audio_guide() {
+        use crate::kana::RomajiSystem;
+
+        let front = "噛[か]み 殺[ころ]す";
+        let cases = [
+            (AudioGuideStyle::Kanji, "噛み殺す"),
+            (AudioGuideStyle::Hiragana, "かみころす"),
+            (AudioGuideStyle::Katakana, "カミコロス"),
+            (AudioGuideStyle::Romaji(RomajiSystem::Hepburn), "kamikorosu"),
+            (AudioGuideStyle::Romaji(RomajiSystem::Kunrei), "kamikorosu"),
+        ];
+        for (style, expected) in cases {
+            assert_eq!(derive_audio_guide(front, style), expected, "{style:?}");
+        }
+        assert_eq!(
+            derive_audio_guide(
+                "抹茶[まっちゃ]",
+                AudioGuideStyle::Romaji(RomajiSystem::Kunrei)
+            ),
+            "mattya"
+        );
+    }
+
+    #[test]
+    fn test_source_card_fields() {
+        let card: SourceCard = serde_json::from_str(
+            r#"{
+                "deckName": "KanKen",
+                "modelName": "Mining",
+                "fields": {
+                    "Word": {"value": "噛み殺す", "order": 0},
+                    "Reading": {"value": "かみころす", "order": 1}
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(card.fields["Word"].value, "噛み殺す");
+        assert_eq!(card.field_names(), vec!["Word", "Reading"]);
+    }
+
+    #[test]
+    fn test_default_profile() {
+        let field = |value: &str, order| Field {
+            value: value.into(),
+            order,
+        };
+        let card = SourceCard {
+            deck_name: "KanKen".into(),
+            model_name: "KanKen".into(),
+            fields: HashMap::from([
+                ("Kanji".into(), field("噛み殺す", 0)),
+                ("Kana".into(), field("かみころす", 1)),
+                ("SentenceBack".into(), field("欠伸を<b>噛み殺す</b>", 2)),
+                ("Picture".into(), field("", 3)),
+            ]),
+        };
+        let p = MappingProfile::default();
+        assert_eq!(p.render("Front", &card).unwrap(), "噛[か]み 殺[ころ]す");
+        assert_eq!(
+            p.render("Back Paragraph", &card).unwrap(),
+            "欠伸を<b>噛み殺す</b>"
+        );
+        assert_eq!(p.render("Audio", &card).unwrap(), "");
+        assert_eq!(p.render("Unmapped", &card).unwrap(), "");
+    }
+
+    #[test]
+    fn test_target_fields_serialization() {
+        let mut fields = GuiAddCardsFields::default();
+        fields.set("Back Paragraph", "欠伸を噛み殺す".into());
+        assert_eq!(fields.get("Back Paragraph"), "欠伸を噛み殺す");
+        assert_eq!(fields.get("Front"), "");
+        assert_eq!(
+            serde_json::to_value(&fields).unwrap(),
+            serde_json::json!({ "Back Paragraph": "欠伸を噛み殺す" })
+        );
+    }
+
+    fn kanken_card() -> SourceCard {
+        let field = |value: &str, order| Field {
+            value: value.into(),
+            order,
+        };
+        SourceCard {
+            deck_name: "KanKenDeck".into(),
+            model_name: "KanKen".into(),
+            fields: HashMap::from([
+                ("Kanji".into(), field("噛み殺す", 0)),
+                ("Kana".into(), field("かみころす", 1)),
+                ("SentenceBack".into(), field("欠伸を<b>噛み殺す</b>\n", 2)),
+                ("Picture".into(), field("<img src=\"a.jpg\">", 3)),
+                ("KankenAudio".into(), field("[sound:a.mp3]", 4)),
+                ("Meaning".into(), field("to stifle a yawn", 5)),
+            ]),
+        }
+    }
+
+    fn target_fields() -> Vec<String> {
+        DEFAULT_TARGET_FIELDS.map(String::from).to_vec()
+    }
+
+    fn overrides(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn compose(
+        o: &[(&str, &str)],
+        prev: Option<&GuiAddCardsFields>,
+        source: Option<&SourceCard>,
+    ) -> GuiAddCardsFields {
+        compose_card(
+            &overrides(o),
+            prev,
+            source,
+            &BTreeMap::new(),
+            &target_fields(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_compose_from_source() {
+        let card = compose(&[], None, Some(&kanken_card()));
+        assert_eq!(card.get("Front"), "噛[か]み 殺[ころ]す");
+        assert_eq!(card.get("Back"), "to stifle a yawn");
+        assert_eq!(
+            card.get("Back Paragraph"),
+            "欠伸を<b>噛み殺す</b><br /><img src=\"a.jpg\">"
+        );
+        assert_eq!(card.get("AudioGuide"), "噛み殺す");
+        assert_eq!(card.get("Audio"), "[sound:a.mp3]");
+    }
+
+    #[test]
+    fn test_compose_override_beats_source() {
+        let card = compose(
+            &[
+                ("Front", " 噛[か]み 殺[ころ]す "),
+                ("Back", "to bite\nto death"),
+            ],
+            None,
+            Some(&kanken_card()),
+        );
+        assert_eq!(card.get("Front"), "噛[か]み 殺[ころ]す");
+        assert_eq!(card.get("Back"), "to bite<br />to death");
+        assert_eq!(card.get("AudioGuide"), "噛み殺す");
+    }
+
+    #[test]
+    fn test_compose_blank_override_ignored() {
+        let card = compose(&[("Back", " \n ")], None, Some(&kanken_card()));
+        assert_eq!(card.get("Back"), "to stifle a yawn");
+    }
+
+    #[test]
+    fn test_compose_override_line_breaks() {
+        let card = compose(&[("Back", "a\r\nb\nc")], None, None);
+        assert_eq!(card.get("Back"), "a<br />b<br />c");
+    }
+
+    #[test]
+    fn test_compose_override_outside_target_fields() {
+        let card = compose(&[("Extra", "x")], None, None);
+        assert_eq!(card.get("Extra"), "");
+        assert!(!card.0.contains_key("Extra"));
+    }
+
+    #[test]
+    fn test_compose_prev_beats_source() {
+        let prev = compose(&[], None, Some(&kanken_card()));
+        let mut other = kanken_card();
+        other.fields.get_mut("Meaning").unwrap().value = "changed".into();
+
+        let card = compose(&[], Some(&prev), Some(&other));
+        assert_eq!(card, prev);
+    }
+
+    #[test]
+    fn test_compose_override_beats_prev() {
+        let prev = compose(&[], None, Some(&kanken_card()));
+        let card = compose(&[("Front", "噛[か]む")], Some(&prev), None);
+        assert_eq!(card.get("Front"), "噛[か]む");
+        assert_eq!(card.get("Back"), prev.get("Back"));
+        assert_eq!(card.get("Audio"), prev.get("Audio"));
+    }
+
+    #[test]
+    fn test_compose_nothing_but_overrides() {
+        let card = compose(&[("Back", "b")], None, None);
+        assert_eq!(card.get("Back"), "b");
+        assert_eq!(card.0.len(), target_fields().len());
+        assert_eq!(card.get("Front"), "");
+    }
+
+    #[test]
+    fn test_compose_uses_profile_for_source_model() {
+        let profile = MappingProfile {
+            templates: BTreeMap::from([("Front".into(), "{{Kana}}".into())]),
+            policies: BTreeMap::new(),
+        };
+        let profiles = BTreeMap::from([("KanKen".into(), profile)]);
+        let card = compose_card(
+            &BTreeMap::new(),
+            None,
+            Some(&kanken_card()),
+            &profiles,
+            &target_fields(),
+        )
+        .unwrap();
+        assert_eq!(card.get("Front"), "かみころす");
+        assert_eq!(card.get("Back"), "");
+    }
+
+    #[test]
+    fn test_compose_template_error() {
+        let profile = MappingProfile {
+            templates: BTreeMap::from([("Back".into(), "{{Meaning|shout}}".into())]),
+            policies: BTreeMap::new(),
+        };
+        let profiles = BTreeMap::from([("KanKen".into(), profile)]);
+        let err = compose_card(
+            &BTreeMap::new(),
+            None,
+            Some(&kanken_card()),
+            &profiles,
+            &target_fields(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            AnkiError::Template {
+                field: "Back".into(),
+                message: "unknown filter `shout`".into(),
+            }
+        );
+    }
+}
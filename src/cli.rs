@@ -0,0 +1,271 @@
+use std::collections::BTreeMap;
+
+use serde_json::json;
+
+use crate::{
+    ankiconnect::Client,
+    card::{derive_audio_guide, SourceCard},
+    config::{AddMode, Overrides, Settings},
+    error::AnkiError,
+    fire::{self, FireOutcome},
+};
+
+pub const USAGE: &str = "\
+usage: anki-copy-card-egui [--url URL] [--api-key KEY] [--api-version N] [COMMAND [OPTIONS]]
+
+Without a command, opens the window.
+
+commands:
+  current    print the card being reviewed
+  preview    print the card that firing would add, without adding it
+  fire       add the card
+
+options for preview and fire:
+  --front VALUE           override the front field
+  --back VALUE            override the back field
+  --audio-guide VALUE     override the audio guide field, which otherwise follows --front
+  --no-follow-front       do not derive the audio guide from --front
+  --field NAME=VALUE      override any field
+  --deck NAME             target deck
+  --model NAME            target note type
+  --tag TAG               add a tag, may be repeated
+
+options for fire:
+  --silent                add with addNote instead of opening the Add dialog
+  --allow-duplicate       let addNote add duplicates
+  --on-duplicate ACTION   cancel (default), add, or update the first existing note
+";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Command {
+    Current,
+    Preview,
+    Fire,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum OnDuplicate {
+    #[default]
+    Cancel,
+    Add,
+    Update,
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    command: Command,
+    front: Option<String>,
+    back: Option<String>,
+    audio_guide: Option<String>,
+    follow_front: bool,
+    fields: BTreeMap<String, String>,
+    deck: Option<String>,
+    model: Option<String>,
+    tags: Vec<String>,
+    silent: bool,
+    allow_duplicate: bool,
+    on_duplicate: OnDuplicate,
+}
+
+fn parse(args: Vec<String>) -> anyhow::Result<Options> {
+    let mut args = args.into_iter();
+    let command = match args.next().as_deref() {
+        Some("current") => Command::Current,
+        Some("preview") => Command::Preview,
+        Some("fire") => Command::Fire,
+        Some(c) => anyhow::bail!("unknown command `{c}`"),
+        None => anyhow::bail!("missing command"),
+    };
+    let mut o = Options {
+        command,
+        front: None,
+        back: None,
+        audio_guide: None,
+        follow_front: true,
+        fields: BTreeMap::new(),
+        deck: None,
+        model: None,
+        tags: vec![],
+        silent: false,
+        allow_duplicate: false,
+        on_duplicate: OnDuplicate::default(),
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow::anyhow!("missing value for {arg}"))
+        };
+        match arg.as_str() {
+            "--front" => o.front = Some(value()?),
+            "--back" => o.back = Some(value()?),
+            "--audio-guide" => o.audio_guide = Some(value()?),
+            "--no-follow-front" => o.follow_front = false,
+            "--field" => {
+                let v = value()?;
+                let (name, v) = v
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("expected NAME=VALUE, got `{v}`"))?;
+                o.fields.insert(name.to_owned(), v.to_owned());
+            }
+            "--deck" => o.deck = Some(value()?),
+            "--model" => o.model = Some(value()?),
+            "--tag" => o.tags.push(value()?),
+            "--silent" => o.silent = true,
+            "--allow-duplicate" => o.allow_duplicate = true,
+            "--on-duplicate" => {
+                o.on_duplicate = match value()?.as_str() {
+                    "cancel" => OnDuplicate::Cancel,
+                    "add" => OnDuplicate::Add,
+                    "update" => OnDuplicate::Update,
+                    v => anyhow::bail!("unknown duplicate action `{v}`"),
+                }
+            }
+            _ => anyhow::bail!("unexpected argument `{arg}`"),
+        }
+    }
+    Ok(o)
+}
+
+/// Runs a headless command and prints its result as JSON. Returns the exit code.
+pub fn run(args: Vec<String>, overrides: &Overrides) -> i32 {
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        print!("{USAGE}");
+        return 0;
+    }
+    let options = match parse(args) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return 2;
+        }
+    };
+
+    let mut settings = match Settings::load() {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("{e}");
+            return 1;
+        }
+    };
+    overrides.apply(&mut settings.connection);
+    let client = Client::new(settings.connection.clone());
+    match execute(&client, settings, options) {
+        Ok((out, code)) => {
+            println!("{out:#}");
+            code
+        }
+        Err(e) => {
+            println!("{:#}", json!({ "error": e.to_string() }));
+            1
+        }
+    }
+}
+
+fn execute(
+    client: &Client,
+    mut settings: Settings,
+    o: Options,
+) -> Result<(serde_json::Value, i32), AnkiError> {
+    if o.command == Command::Current {
+        let card = client.gui_current_card::<SourceCard>()?;
+        return Ok((json!(card), 0));
+    }
+
+    let target = &mut settings.target;
+    if let Some(deck) = o.deck {
+        target.deck_name = deck;
+    }
+    if let Some(model) = o.model {
+        target.model_name = model;
+    }
+    target.tags.extend(o.tags);
+    if o.silent {
+        settings.add.mode = AddMode::Silent;
+    }
+    settings.add.allow_duplicate |= o.allow_duplicate;
+
+    let mut custom = o.fields;
+    if let Some(front) = o.front {
+        if o.follow_front && o.audio_guide.is_none() {
+            let audio_guide = derive_audio_guide(&front, target.audio_guide_style);
+            custom.insert(target.audio_guide_field.clone(), audio_guide);
+        }
+        custom.insert(target.front_field.clone(), front);
+    }
+    if let Some(back) = o.back {
+        custom.insert(target.back_field.clone(), back);
+    }
+    if let Some(audio_guide) = o.audio_guide {
+        custom.insert(target.audio_guide_field.clone(), audio_guide);
+    }
+
+    let fields = client.model_field_names(&target.model_name)?;
+    if o.command == Command::Preview {
+        let fired = fire::compose(client, &settings, &fields, None, custom)?;
+        return Ok((json!(fired), 0));
+    }
+
+    match fire::fire_card(client, &settings, &fields, None, custom)? {
+        FireOutcome::Added(fired) => Ok((json!({ "outcome": "added", "fired": fired }), 0)),
+        FireOutcome::Updated(fired) => Ok((json!({ "outcome": "updated", "fired": fired }), 0)),
+        FireOutcome::Duplicates(fired, existing) => {
+            let ids: Vec<_> = existing.iter().map(|n| n.note_id).collect();
+            match o.on_duplicate {
+                OnDuplicate::Cancel => Ok((
+                    json!({ "outcome": "duplicate", "fired": fired, "existing": ids }),
+                    1,
+                )),
+                OnDuplicate::Add => {
+                    let fired = fire::add_card(client, &settings, fired, true)?;
+                    Ok((json!({ "outcome": "added", "fired": fired }), 0))
+                }
+                OnDuplicate::Update => {
+                    let fired = fire::update_card(client, &settings, ids[0], fired)?;
+                    Ok((json!({ "outcome": "updated", "fired": fired }), 0))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(a: &[&str]) -> Vec<String> {
+        a.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let o = parse(args(&[
+            "fire",
+            "--front",
+            "噛[か]み",
+            "--back",
+            "to bite",
+            "--field",
+            "Back Paragraph=a=b",
+            "--tag",
+            "x",
+            "--tag",
+            "y",
+            "--silent",
+            "--on-duplicate",
+            "update",
+        ]))
+        .unwrap();
+        assert_eq!(o.command, Command::Fire);
+        assert_eq!(o.front.as_deref(), Some("噛[か]み"));
+        assert_eq!(o.back.as_deref(), Some("to bite"));
+        assert_eq!(o.fields["Back Paragraph"], "a=b");
+        assert_eq!(o.tags, vec!["x", "y"]);
+        assert!(o.silent && o.follow_front);
+        assert_eq!(o.on_duplicate, OnDuplicate::Update);
+
+        assert!(parse(args(&["burn"])).is_err());
+        assert!(parse(args(&["fire", "--front"])).is_err());
+        assert!(parse(args(&["fire", "--field", "Back"])).is_err());
+    }
+}
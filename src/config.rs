@@ -0,0 +1,415 @@
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ankiconnect::{DuplicateScopeOptions, NoteOptions},
+    card::MappingProfile,
+    kana::RomajiSystem,
+};
+
+const CONFIG_ENV: &str = "ANKI_COPY_CARD_CONFIG";
+const URL_ENV: &str = "ANKICONNECT_URL";
+const KEY_ENV: &str = "ANKICONNECT_KEY";
+const VERSION_ENV: &str = "ANKICONNECT_VERSION";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Connection {
+    pub url: String,
+    pub api_key: Option<String>,
+    pub version: u8,
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self {
+            url: "http://localhost:8765".into(),
+            api_key: None,
+            version: 6,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Target {
+    pub deck_name: String,
+    pub model_name: String,
+    pub tags: Vec<String>,
+    /// Adds a `from::<source deck>` tag.
+    pub from_tag: bool,
+    /// The field edited as the front, which the audio guide can follow.
+    pub front_field: String,
+    /// The field dictionary senses are inserted into.
+    pub back_field: String,
+    pub audio_guide_field: String,
+    /// How the audio guide is written when it follows the front.
+    pub audio_guide_style: AudioGuideStyle,
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Self {
+            deck_name: "Immersion".into(),
+            model_name: "Immersion".into(),
+            tags: vec!["Immersion".into()],
+            from_tag: true,
+            front_field: "Front".into(),
+            back_field: "Back".into(),
+            audio_guide_field: "AudioGuide".into(),
+            audio_guide_style: AudioGuideStyle::default(),
+        }
+    }
+}
+
+impl Target {
+    pub fn tags(&self, source_deck: Option<&str>) -> Vec<String> {
+        let mut tags = self.tags.clone();
+        if let Some(deck) = source_deck.filter(|_| self.from_tag) {
+            // tags cannot contain spaces
+            let tag = format!(
+                "from::{}",
+                deck.split_whitespace().collect::<Vec<_>>().join("_")
+            );
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        tags
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AudioGuideStyle {
+    /// The front without its furigana, as in `噛み殺す`.
+    #[default]
+    Kanji,
+    /// The reading of the front, as in `かみころす`.
+    Hiragana,
+    Katakana,
+    Romaji(RomajiSystem),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AddMode {
+    /// Opens Anki's Add dialog with `guiAddCards` and waits for the user to confirm.
+    #[default]
+    Dialog,
+    /// Adds the note straight away with `addNote`.
+    Silent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AddOptions {
+    pub mode: AddMode,
+    /// Looks for notes with the same front in the target deck before adding.
+    pub check_duplicates: bool,
+    pub allow_duplicate: bool,
+    /// Checks duplicates in the target deck only instead of the whole collection.
+    pub scope_deck: bool,
+    pub check_children: bool,
+    pub check_all_models: bool,
+    /// Copies the media the card references, so it survives the source note.
+    pub copy_media: bool,
+}
+
+impl Default for AddOptions {
+    fn default() -> Self {
+        Self {
+            mode: AddMode::default(),
+            check_duplicates: true,
+            allow_duplicate: false,
+            scope_deck: false,
+            check_children: false,
+            check_all_models: false,
+            copy_media: true,
+        }
+    }
+}
+
+impl AddOptions {
+    pub fn note_options(&self, deck_name: &str) -> NoteOptions {
+        NoteOptions {
+            allow_duplicate: self.allow_duplicate,
+            duplicate_scope: self.scope_deck.then(|| "deck".into()),
+            duplicate_scope_options: Some(DuplicateScopeOptions {
+                deck_name: self.scope_deck.then(|| deck_name.to_owned()),
+                check_children: self.check_children,
+                check_all_models: self.check_all_models,
+            }),
+        }
+    }
+}
+
+/// A local text-to-speech command, such as piper, espeak-ng or Open JTalk,
+/// that voices the audio guide when the audio field is blank.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tts {
+    pub enabled: bool,
+    pub program: String,
+    /// `{text}` and `{output}` are replaced by the audio guide and the file to
+    /// write. Without `{text}`, the audio guide is written to the standard input.
+    pub args: Vec<String>,
+    /// The extension of the file written, such as `wav` or `mp3`.
+    pub extension: String,
+    pub audio_field: String,
+}
+
+impl Default for Tts {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            program: "espeak-ng".into(),
+            args: ["-v", "ja", "-w", "{output}", "{text}"]
+                .map(String::from)
+                .to_vec(),
+            extension: "wav".into(),
+            audio_field: "Audio".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccentFormat {
+    /// The reading, overlined where the pitch is high.
+    #[default]
+    Html,
+    /// A graph of the pitch over the reading.
+    Svg,
+}
+
+/// A local pitch accent dataset, such as Kanjium's `accents.txt`, that annotates
+/// the front's word when the pitch field is blank.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PitchAccent {
+    pub enabled: bool,
+    pub path: String,
+    pub field: String,
+    pub format: AccentFormat,
+}
+
+impl Default for PitchAccent {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: String::new(),
+            field: "Pitch".into(),
+            format: AccentFormat::Html,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub connection: Connection,
+    pub target: Target,
+    pub add: AddOptions,
+    pub tts: Tts,
+    pub pitch: PitchAccent,
+    /// Keyed on the source card's note type.
+    pub profiles: BTreeMap<String, MappingProfile>,
+}
+
+impl Settings {
+    pub fn path() -> Option<PathBuf> {
+        if let Some(p) = env::var_os(CONFIG_ENV) {
+            return Some(p.into());
+        }
+        let base = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+            .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))?;
+        Some(base.join("anki-copy-card-egui").join("settings.json"))
+    }
+
+    /// The defaults when there is no settings file yet.
+    pub fn load() -> anyhow::Result<Self> {
+        match Self::path() {
+            Some(path) => Self::load_from(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load_from(path: &Path) -> anyhow::Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => anyhow::bail!("cannot read {}: {e}", path.display()),
+        };
+        serde_json::from_str(&text)
+            .map_err(|e| anyhow::anyhow!("cannot parse {}: {e}", path.display()))
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path = Self::path().ok_or_else(|| anyhow::anyhow!("no config directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Connection settings given on the command line or through the environment.
+/// These take precedence over the saved settings but are not written back.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Overrides {
+    pub url: Option<String>,
+    pub api_key: Option<String>,
+    pub version: Option<u8>,
+}
+
+impl Overrides {
+    pub fn from_env() -> Self {
+        Self {
+            url: env::var(URL_ENV).ok(),
+            api_key: env::var(KEY_ENV).ok(),
+            version: env::var(VERSION_ENV).ok().and_then(|v| v.parse().ok()),
+        }
+    }
+
+    /// Parses `--url`, `--api-key` and `--api-version`, both as `--flag value`
+    /// and `--flag=value`. Unknown arguments are returned untouched.
+    pub fn parse_args(
+        &mut self,
+        args: impl IntoIterator<Item = String>,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut rest = vec![];
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((f, v)) if f.starts_with("--") => (f.to_owned(), Some(v.to_owned())),
+                _ => (arg.clone(), None),
+            };
+            if !matches!(flag.as_str(), "--url" | "--api-key" | "--api-version") {
+                rest.push(arg);
+                continue;
+            }
+            let value = match inline {
+                Some(v) => v,
+                None => args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("missing value for {flag}"))?,
+            };
+            match flag.as_str() {
+                "--url" => self.url = Some(value),
+                "--api-key" => self.api_key = Some(value),
+                _ => self.version = Some(value.parse()?),
+            }
+        }
+        Ok(rest)
+    }
+
+    pub fn apply(&self, c: &mut Connection) {
+        if let Some(url) = &self.url {
+            c.url.clone_from(url);
+        }
+        if let Some(key) = &self.api_key {
+            c.api_key = Some(key.clone()).filter(|k| !k.is_empty());
+        }
+        if let Some(version) = self.version {
+            c.version = version;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_tags() {
+        let t = Target::default();
+        assert_eq!(t.tags(None), vec!["Immersion"]);
+        assert_eq!(
+            t.tags(Some("KanKen Deck::Level 1")),
+            vec!["Immersion", "from::KanKen_Deck::Level_1"]
+        );
+
+        let t = Target {
+            from_tag: false,
+            ..Default::default()
+        };
+        assert_eq!(t.tags(Some("KanKenDeck")), vec!["Immersion"]);
+    }
+
+    #[test]
+    fn test_note_options() {
+        let add = AddOptions {
+            scope_deck: true,
+            check_children: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_value(add.note_options("Immersion")).unwrap(),
+            serde_json::json!({
+                "allowDuplicate": false,
+                "duplicateScope": "deck",
+                "duplicateScopeOptions": {
+                    "deckName": "Immersion",
+                    "checkChildren": true,
+                    "checkAllModels": false,
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_load_from() {
+        let path = env::temp_dir().join(format!(
+            "anki-copy-card-settings-{}.json",
+            std::process::id()
+        ));
+        assert_eq!(Settings::load_from(&path).unwrap(), Settings::default());
+
+        fs::write(&path, r#"{"target": {"deck_name": "Mining"}}"#).unwrap();
+        assert_eq!(
+            Settings::load_from(&path).unwrap().target.deck_name,
+            "Mining"
+        );
+
+        fs::write(&path, r#"{"target": {"audio_guide_style": "Braille"}}"#).unwrap();
+        let e = Settings::load_from(&path).unwrap_err().to_string();
+        assert!(e.contains("cannot parse"), "{e}");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let mut o = Overrides::default();
+        let rest = o
+            .parse_args(
+                [
+                    "--url",
+                    "http://10.0.0.2:8766",
+                    "--api-key=secret",
+                    "x",
+                    "--api-version",
+                    "5",
+                ]
+                .map(String::from),
+            )
+            .unwrap();
+        assert_eq!(rest, vec!["x".to_string()]);
+
+        let mut c = Connection::default();
+        o.apply(&mut c);
+        assert_eq!(
+            c,
+            Connection {
+                url: "http://10.0.0.2:8766".into(),
+                api_key: Some("secret".into()),
+                version: 5,
+            }
+        );
+    }
+}
@@ -0,0 +1,591 @@
+//! An offline dictionary, imported from JMdict XML, EDICT or a Yomichan zip into
+//! a cache of one JSON entry per line, and an index of the byte offsets of the
+//! entries for each written form and reading.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Settings, kana};
+
+const ENTRIES: &str = "entries.jsonl";
+const INDEX: &str = "index.tsv";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub kanji: Vec<String>,
+    pub readings: Vec<String>,
+    pub senses: Vec<Sense>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Sense {
+    /// Part of speech codes, such as `v5s` or `n`.
+    pub pos: Vec<String>,
+    pub glosses: Vec<String>,
+}
+
+impl Sense {
+    pub fn text(&self) -> String {
+        self.glosses.join("; ")
+    }
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Reads the English glosses of JMdict's XML. A sense without parts of speech
+/// shares those of the sense before it, as JMdict intends.
+pub fn parse_jmdict(xml: &str) -> Vec<Entry> {
+    let entry = Regex::new(r"(?s)<entry>(.*?)</entry>").unwrap();
+    let keb = Regex::new(r"<keb>(.*?)</keb>").unwrap();
+    let reb = Regex::new(r"<reb>(.*?)</reb>").unwrap();
+    let sense = Regex::new(r"(?s)<sense>(.*?)</sense>").unwrap();
+    let pos = Regex::new(r"<pos>&?([^;<]*);?</pos>").unwrap();
+    let gloss = Regex::new(r"<gloss([^>]*)>(.*?)</gloss>").unwrap();
+
+    entry
+        .captures_iter(xml)
+        .map(|e| {
+            let e = &e[1];
+            let mut senses: Vec<Sense> = vec![];
+            for s in sense.captures_iter(e) {
+                let s = &s[1];
+                let mut p: Vec<String> = pos.captures_iter(s).map(|c| c[1].to_owned()).collect();
+                if p.is_empty() {
+                    p = senses.last().map(|l| l.pos.clone()).unwrap_or_default();
+                }
+                let glosses: Vec<String> = gloss
+                    .captures_iter(s)
+                    .filter(|g| !g[1].contains("xml:lang") || g[1].contains("\"eng\""))
+                    .map(|g| unescape(&g[2]))
+                    .collect();
+                if !glosses.is_empty() {
+                    senses.push(Sense { pos: p, glosses });
+                }
+            }
+            Entry {
+                kanji: keb.captures_iter(e).map(|c| unescape(&c[1])).collect(),
+                readings: reb.captures_iter(e).map(|c| unescape(&c[1])).collect(),
+                senses,
+            }
+        })
+        .filter(|e| !e.senses.is_empty())
+        .collect()
+}
+
+/// EDICT's part-of-speech codes, besides the archaic `v2` and `v4` verbs.
+const POS: &[&str] = &[
+    "adj",
+    "adj-f",
+    "adj-i",
+    "adj-ix",
+    "adj-kari",
+    "adj-ku",
+    "adj-na",
+    "adj-nari",
+    "adj-no",
+    "adj-pn",
+    "adj-shiku",
+    "adj-t",
+    "adv",
+    "adv-to",
+    "aux",
+    "aux-adj",
+    "aux-v",
+    "conj",
+    "cop",
+    "ctr",
+    "exp",
+    "int",
+    "n",
+    "n-adv",
+    "n-pr",
+    "n-pref",
+    "n-suf",
+    "n-t",
+    "num",
+    "pn",
+    "pref",
+    "prt",
+    "suf",
+    "unc",
+    "v-unspec",
+    "v1",
+    "v1-s",
+    "v5aru",
+    "v5b",
+    "v5g",
+    "v5k",
+    "v5k-s",
+    "v5m",
+    "v5n",
+    "v5r",
+    "v5r-i",
+    "v5s",
+    "v5t",
+    "v5u",
+    "v5u-s",
+    "v5uru",
+    "vi",
+    "vk",
+    "vn",
+    "vr",
+    "vs",
+    "vs-c",
+    "vs-i",
+    "vs-s",
+    "vt",
+    "vz",
+];
+
+fn is_pos(code: &str) -> bool {
+    POS.contains(&code) || code.starts_with("v2") || code.starts_with("v4")
+}
+
+/// Reads EDICT or EDICT2 lines such as
+/// `噛み殺す;かみ殺す [かみころす] /(v5s,vt) (1) to stifle (a yawn)/(2) to bite to death/EntL1594690X/`.
+pub fn parse_edict(text: &str) -> Vec<Entry> {
+    let marker = Regex::new(r"\([^)]*\)").unwrap();
+    let forms = |s: &str| -> Vec<String> {
+        s.split(';')
+            .map(|f| marker.replace_all(f, "").trim().to_owned())
+            .filter(|f| !f.is_empty())
+            .collect()
+    };
+
+    text.lines()
+        .filter(|l| !l.starts_with('#') && !l.starts_with('　'))
+        .filter_map(|line| {
+            let (head, body) = line.split_once(" /")?;
+            let (kanji, readings) = match head.split_once(" [") {
+                Some((k, r)) => (forms(k), forms(r.trim_end_matches(']'))),
+                None => (vec![], forms(head)),
+            };
+
+            let mut senses: Vec<Sense> = vec![];
+            let mut pos: Vec<String> = vec![];
+            for part in body.split('/').map(str::trim) {
+                if part.is_empty() || part.starts_with("EntL") || part == "(P)" {
+                    continue;
+                }
+                let mut rest = part;
+                let mut new_sense = senses.is_empty();
+                // tags like `(uk)` or `(in Buddhism)` are kept with the gloss
+                let mut kept: Vec<&str> = vec![];
+                while let Some(tag) = rest.strip_prefix('(').and_then(|r| r.split_once(')')) {
+                    let (inner, after) = tag;
+                    if inner.parse::<u32>().is_ok() {
+                        new_sense = true;
+                    } else if inner.split(',').all(|p| is_pos(p.trim())) {
+                        pos = inner.split(',').map(|p| p.trim().to_owned()).collect();
+                    } else {
+                        kept.push(&rest[..inner.len() + 2]);
+                    }
+                    rest = after.trim_start();
+                }
+                kept.push(rest);
+                let gloss = kept.join(" ").trim_end().to_owned();
+                if new_sense || senses.is_empty() {
+                    senses.push(Sense {
+                        pos: pos.clone(),
+                        glosses: vec![],
+                    });
+                }
+                senses.last_mut().unwrap().glosses.push(gloss);
+            }
+            let senses: Vec<Sense> = senses
+                .into_iter()
+                .filter(|s| !s.glosses.is_empty())
+                .collect();
+            (!senses.is_empty()).then_some(Entry {
+                kanji,
+                readings,
+                senses,
+            })
+        })
+        .collect()
+}
+
+/// Collects the text of a Yomichan glossary item, which may be structured content.
+fn glossary_text(v: &serde_json::Value, out: &mut String) {
+    match v {
+        serde_json::Value::String(s) => out.push_str(s),
+        serde_json::Value::Array(a) => a.iter().for_each(|v| glossary_text(v, out)),
+        serde_json::Value::Object(o) => {
+            if let Some(text) = o.get("text") {
+                glossary_text(text, out);
+            } else if let Some(content) = o.get("content") {
+                glossary_text(content, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Reads the `term_bank_*.json` files of a Yomichan dictionary zip.
+pub fn parse_yomichan(zip: impl Read + Seek) -> anyhow::Result<Vec<Entry>> {
+    let mut archive = zip::ZipArchive::new(zip)?;
+    let mut names: Vec<String> = archive
+        .file_names()
+        .filter(|n| n.starts_with("term_bank_") && n.ends_with(".json"))
+        .map(String::from)
+        .collect();
+    names.sort();
+
+    let mut entries = vec![];
+    for name in names {
+        let rows: Vec<Vec<serde_json::Value>> =
+            serde_json::from_reader(BufReader::new(archive.by_name(&name)?))?;
+        for row in rows {
+            let text = |i: usize| row.get(i).and_then(|v| v.as_str()).unwrap_or_default();
+            let (expression, reading) = (text(0), text(1));
+            let glosses: Vec<String> = row
+                .get(5)
+                .and_then(|g| g.as_array())
+                .into_iter()
+                .flatten()
+                .map(|g| {
+                    let mut s = String::new();
+                    glossary_text(g, &mut s);
+                    s
+                })
+                .filter(|g| !g.trim().is_empty())
+                .collect();
+            if expression.is_empty() || glosses.is_empty() {
+                continue;
+            }
+            let kanji =
+                (!reading.is_empty() && reading != expression).then(|| expression.to_owned());
+            entries.push(Entry {
+                readings: vec![kanji.as_ref().map_or(expression, |_| reading).to_owned()],
+                kanji: kanji.into_iter().collect(),
+                senses: vec![Sense {
+                    pos: text(2).split_whitespace().map(String::from).collect(),
+                    glosses,
+                }],
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Reads UTF-8, or EUC-JP as the original EDICT files are encoded.
+fn read_text(path: &Path) -> anyhow::Result<String> {
+    let bytes = fs::read(path)?;
+    match String::from_utf8(bytes) {
+        Ok(text) => Ok(text),
+        Err(e) => {
+            let (text, had_errors) = encoding_rs::EUC_JP.decode_without_bom_handling(e.as_bytes());
+            if had_errors {
+                anyhow::bail!("{} is neither UTF-8 nor EUC-JP", path.display());
+            }
+            Ok(text.into_owned())
+        }
+    }
+}
+
+/// Reads a `.zip` as Yomichan, and other files as JMdict if they look like XML, else as EDICT.
+pub fn read_source(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let is_zip = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("zip"));
+    if is_zip {
+        return parse_yomichan(File::open(path)?);
+    }
+    let text = read_text(path)?;
+    let entries = if text.contains("<JMdict") || text.contains("<entry>") {
+        parse_jmdict(&text)
+    } else {
+        parse_edict(&text)
+    };
+    if entries.is_empty() {
+        anyhow::bail!("no dictionary entries found in {}", path.display());
+    }
+    Ok(entries)
+}
+
+#[derive(Debug)]
+pub struct Dictionary {
+    dir: PathBuf,
+    /// Byte offsets into the entries file, keyed on written forms and readings.
+    index: BTreeMap<String, Vec<u64>>,
+}
+
+impl Dictionary {
+    /// Next to the settings file.
+    pub fn default_dir() -> Option<PathBuf> {
+        Some(Settings::path()?.parent()?.join("dictionary"))
+    }
+
+    pub fn build(entries: &[Entry], dir: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut out = BufWriter::new(File::create(dir.join(ENTRIES))?);
+        let mut index: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+        let mut offset = 0;
+        for entry in entries {
+            let line = serde_json::to_string(entry)? + "\n";
+            out.write_all(line.as_bytes())?;
+            for key in entry.kanji.iter().chain(&entry.readings) {
+                let offsets = index.entry(key.clone()).or_default();
+                if !offsets.contains(&offset) {
+                    offsets.push(offset);
+                }
+            }
+            offset += line.len() as u64;
+        }
+        out.flush()?;
+
+        let mut out = BufWriter::new(File::create(dir.join(INDEX))?);
+        for (key, offsets) in &index {
+            let offsets: Vec<String> = offsets.iter().map(u64::to_string).collect();
+            writeln!(out, "{key}\t{}", offsets.join(","))?;
+        }
+        out.flush()?;
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            index,
+        })
+    }
+
+    pub fn import(source: &Path, dir: &Path) -> anyhow::Result<Self> {
+        Self::build(&read_source(source)?, dir)
+    }
+
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        let mut index = BTreeMap::new();
+        for line in BufReader::new(File::open(dir.join(INDEX))?).lines() {
+            let line = line?;
+            let Some((key, offsets)) = line.split_once('\t') else {
+                continue;
+            };
+            let offsets = offsets
+                .split(',')
+                .map(str::parse)
+                .collect::<Result<Vec<u64>, _>>()?;
+            index.insert(key.to_owned(), offsets);
+        }
+        Ok(Self {
+            dir: dir.to_owned(),
+            index,
+        })
+    }
+
+    /// The number of written forms and readings indexed.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Finds the entries written or read as `word`, with katakana read as hiragana.
+    pub fn lookup(&self, word: &str) -> anyhow::Result<Vec<Entry>> {
+        let word = word.trim();
+        let mut offsets: Vec<u64> = vec![];
+        for key in [word.to_owned(), kana::to_hiragana(word)] {
+            for &o in self.index.get(&key).into_iter().flatten() {
+                if !offsets.contains(&o) {
+                    offsets.push(o);
+                }
+            }
+        }
+        if offsets.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut file = BufReader::new(File::open(self.dir.join(ENTRIES))?);
+        offsets
+            .into_iter()
+            .map(|o| {
+                file.seek(SeekFrom::Start(o))?;
+                let mut line = String::new();
+                file.read_line(&mut line)?;
+                Ok(serde_json::from_str(&line)?)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const JMDICT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE JMdict [
+<!ENTITY v5s "Godan verb with 'su' ending">
+]>
+<JMdict>
+<entry>
+<ent_seq>1594690</ent_seq>
+<k_ele><keb>噛み殺す</keb></k_ele>
+<k_ele><keb>かみ殺す</keb></k_ele>
+<r_ele><reb>かみころす</reb></r_ele>
+<sense>
+<pos>&v5s;</pos>
+<pos>&vt;</pos>
+<gloss>to stifle (a yawn, laugh, etc.)</gloss>
+<gloss>to suppress</gloss>
+<gloss xml:lang="ger">ein Gähnen unterdrücken</gloss>
+</sense>
+<sense>
+<gloss>to bite to death</gloss>
+</sense>
+</entry>
+<entry>
+<r_ele><reb>あくび</reb></r_ele>
+<sense><gloss xml:lang="fre">bâillement</gloss></sense>
+</entry>
+</JMdict>"#;
+
+    fn kamikorosu() -> Entry {
+        Entry {
+            kanji: vec!["噛み殺す".into(), "かみ殺す".into()],
+            readings: vec!["かみころす".into()],
+            senses: vec![
+                Sense {
+                    pos: vec!["v5s".into(), "vt".into()],
+                    glosses: vec![
+                        "to stifle (a yawn, laugh, etc.)".into(),
+                        "to suppress".into(),
+                    ],
+                },
+                Sense {
+                    pos: vec!["v5s".into(), "vt".into()],
+                    glosses: vec!["to bite to death".into()],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_parse_jmdict() {
+        assert_eq!(parse_jmdict(JMDICT), vec![kamikorosu()]);
+    }
+
+    #[test]
+    fn test_parse_edict() {
+        let text = "　？？？ /EDICT, EDICT_SUB(P), EDICT2/\n\
+            噛み殺す;かみ殺す [かみころす] /(v5s,vt) (1) to stifle (a yawn, laugh, etc.)/to suppress/(2) to bite to death/EntL1594690X/\n\
+            あくび /(n) yawn/(P)/\n\
+            悟り [さとり] /(n) (1) (in Buddhism) enlightenment/(2) (uk) comprehension/\n\
+            一 [いち] /(num,pref) (1) one/(n,adj-no) (2) best/first/\n";
+        let entries = parse_edict(text);
+        assert_eq!(entries[0], kamikorosu());
+        assert_eq!(
+            entries[1],
+            Entry {
+                kanji: vec![],
+                readings: vec!["あくび".into()],
+                senses: vec![Sense {
+                    pos: vec!["n".into()],
+                    glosses: vec!["yawn".into()],
+                }],
+            }
+        );
+        assert_eq!(
+            entries[2].senses,
+            vec![
+                Sense {
+                    pos: vec!["n".into()],
+                    glosses: vec!["(in Buddhism) enlightenment".into()],
+                },
+                Sense {
+                    pos: vec!["n".into()],
+                    glosses: vec!["(uk) comprehension".into()],
+                },
+            ]
+        );
+        assert_eq!(
+            entries[3].senses,
+            vec![
+                Sense {
+                    pos: vec!["num".into(), "pref".into()],
+                    glosses: vec!["one".into()],
+                },
+                Sense {
+                    pos: vec!["n".into(), "adj-no".into()],
+                    glosses: vec!["best".into(), "first".into()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_euc_jp() {
+        let path =
+            std::env::temp_dir().join(format!("anki-copy-card-edict-{}", std::process::id()));
+        let (bytes, ..) = encoding_rs::EUC_JP.encode("欠伸 [あくび] /(n) yawn/(P)/\n");
+        fs::write(&path, bytes).unwrap();
+        let entries = read_source(&path).unwrap();
+        assert_eq!(entries[0].kanji, vec!["欠伸"]);
+        assert_eq!(entries[0].readings, vec!["あくび"]);
+
+        fs::write(&path, b"\xff\xff /(n) yawn/\n").unwrap();
+        assert!(read_source(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_yomichan() {
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        let options = zip::write::SimpleFileOptions::default();
+        zip.start_file("index.json", options).unwrap();
+        zip.write_all(br#"{"title": "JMdict"}"#).unwrap();
+        zip.start_file("term_bank_1.json", options).unwrap();
+        let terms = serde_json::json!([
+            ["噛み殺す", "かみころす", "v5 vt", "v5", 0, ["to stifle", "to suppress"], 1, ""],
+            ["あくび", "", "n", "", 0, [{ "type": "structured-content", "content": [
+                { "tag": "span", "content": "yawn" }
+            ] }], 2, ""],
+        ]);
+        zip.write_all(terms.to_string().as_bytes()).unwrap();
+        let data = zip.finish().unwrap();
+
+        let entries = parse_yomichan(data).unwrap();
+        assert_eq!(entries[0].kanji, vec!["噛み殺す"]);
+        assert_eq!(entries[0].readings, vec!["かみころす"]);
+        assert_eq!(entries[0].senses[0].pos, vec!["v5", "vt"]);
+        assert_eq!(entries[0].senses[0].text(), "to stifle; to suppress");
+        assert!(entries[1].kanji.is_empty());
+        assert_eq!(entries[1].readings, vec!["あくび"]);
+        assert_eq!(entries[1].senses[0].glosses, vec!["yawn"]);
+    }
+
+    #[test]
+    fn test_build_and_lookup() {
+        let dir = std::env::temp_dir().join(format!("anki-copy-card-dict-{}", std::process::id()));
+        let yawn = Entry {
+            kanji: vec!["欠伸".into()],
+            readings: vec!["あくび".into()],
+            senses: vec![Sense {
+                pos: vec!["n".into()],
+                glosses: vec!["yawn".into()],
+            }],
+        };
+        let built = Dictionary::build(&[kamikorosu(), yawn.clone()], &dir).unwrap();
+        assert_eq!(built.len(), 5);
+
+        let dict = Dictionary::open(&dir).unwrap();
+        assert_eq!(dict.lookup("噛み殺す").unwrap(), vec![kamikorosu()]);
+        assert_eq!(dict.lookup("かみ殺す").unwrap(), vec![kamikorosu()]);
+        assert_eq!(dict.lookup(" アクビ ").unwrap(), vec![yawn]);
+        assert!(dict.lookup("噛む").unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+use std::fmt;
+
+use regex::Regex;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnkiError {
+    Connection(String),
+    Http(u16),
+    Anki(String),
+    Deserialize {
+        missing_field: Option<String>,
+        message: String,
+    },
+    NoCurrentCard,
+    Template {
+        field: String,
+        message: String,
+    },
+    Tts(String),
+    Pitch(String),
+}
+
+impl AnkiError {
+    pub fn deserialize(e: &serde_json::Error) -> Self {
+        let message = e.to_string();
+        let r = Regex::new(r"missing field `([^`]*)`").unwrap();
+        let missing_field = r.captures(&message).map(|c| c[1].to_owned());
+        Self::Deserialize {
+            missing_field,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for AnkiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connection(e) => write!(f, "cannot connect to AnkiConnect: {e}"),
+            Self::Http(code) => write!(f, "AnkiConnect responded with HTTP {code}"),
+            Self::Anki(e) => write!(f, "AnkiConnect error: {e}"),
+            Self::Deserialize {
+                missing_field: Some(field),
+                ..
+            } => write!(f, "unexpected response: missing field `{field}`"),
+            Self::Deserialize { message, .. } => write!(f, "unexpected response: {message}"),
+            Self::NoCurrentCard => write!(f, "no card is being reviewed"),
+            Self::Template { field, message } => {
+                write!(f, "invalid template for {field}: {message}")
+            }
+            Self::Tts(e) => write!(f, "text-to-speech failed: {e}"),
+            Self::Pitch(e) => write!(f, "cannot read pitch accents: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AnkiError {}
+
+impl From<ureq::Error> for AnkiError {
+    fn from(e: ureq::Error) -> Self {
+        match e {
+            ureq::Error::Status(code, _) => Self::Http(code),
+            ureq::Error::Transport(t) => Self::Connection(t.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AnkiError;
+
+    #[test]
+    fn test_deserialize_missing_field() {
+        #[allow(unused)]
+        #[derive(Debug, serde::Deserialize)]
+        struct Card {
+            kanji: String,
+        }
+
+        let e = serde_json::from_str::<Card>("{}").unwrap_err();
+        let AnkiError::Deserialize { missing_field, .. } = AnkiError::deserialize(&e) else {
+            panic!();
+        };
+        assert_eq!(missing_field.as_deref(), Some("kanji"));
+    }
+}
@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ankiconnect::{Client, Note, NoteInfo},
+    card::{compose_card, GuiAddCardsFields, SourceCard},
+    config::{AddMode, Settings},
+    error::AnkiError,
+    media, pitch, tts,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fired {
+    pub card: GuiAddCardsFields,
+    pub source: Option<SourceCard>,
+    /// Known once the note is sent. The Add dialog reports the ID its note will
+    /// have, which never exists if the user cancels the dialog.
+    pub note_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub at: DateTime<Local>,
+    pub fired: Fired,
+    pub updated: bool,
+    pub deleted: bool,
+}
+
+#[derive(Debug)]
+pub enum FireOutcome {
+    Added(Fired),
+    Updated(Fired),
+    /// Nothing was sent yet, since notes with the same front already exist.
+    Duplicates(Fired, Vec<NoteInfo>),
+}
+
+pub fn fire_card(
+    client: &Client,
+    settings: &Settings,
+    fields: &[String],
+    prev_card: Option<Fired>,
+    custom: BTreeMap<String, String>,
+) -> Result<FireOutcome, AnkiError> {
+    let fired = compose(client, settings, fields, prev_card, custom)?;
+
+    if settings.add.check_duplicates {
+        let existing = find_duplicates(client, settings, &fired.card)?;
+        if !existing.is_empty() {
+            return Ok(FireOutcome::Duplicates(fired, existing));
+        }
+    }
+
+    add_card(client, settings, fired, false).map(FireOutcome::Added)
+}
+
+/// Builds the card that firing would send, reading the current card unless
+/// a previous card is given.
+pub fn compose(
+    client: &Client,
+    settings: &Settings,
+    fields: &[String],
+    prev_card: Option<Fired>,
+    custom: BTreeMap<String, String>,
+) -> Result<Fired, AnkiError> {
+    let source = match &prev_card {
+        Some(prev) => prev.source.clone(),
+        None => Some(
+            client
+                .gui_current_card::<SourceCard>()?
+                .ok_or(AnkiError::NoCurrentCard)?,
+        ),
+    };
+    let mut new_card = compose_card(
+        &custom,
+        prev_card.as_ref().map(|p| &p.card),
+        source.as_ref().filter(|_| prev_card.is_none()),
+        &settings.profiles,
+        fields,
+    )?;
+    if settings.pitch.enabled {
+        pitch::fill_accent(&settings.pitch, &settings.target.front_field, &mut new_card)?;
+    }
+
+    Ok(Fired {
+        card: new_card,
+        source,
+        note_id: None,
+    })
+}
+
+/// `force` lets a silent add through even if duplicates are not allowed.
+pub fn add_card(
+    client: &Client,
+    settings: &Settings,
+    mut fired: Fired,
+    force: bool,
+) -> Result<Fired, AnkiError> {
+    prepare_media(client, settings, &mut fired.card)?;
+    let target = &settings.target;
+    let mut note = Note {
+        deck_name: target.deck_name.clone(),
+        model_name: target.model_name.clone(),
+        fields: &fired.card,
+        tags: target.tags(fired.source.as_ref().map(|s| s.deck_name.as_str())),
+        options: None,
+    };
+    fired.note_id = match settings.add.mode {
+        AddMode::Dialog => Some(client.gui_add_cards(&note)?),
+        AddMode::Silent => {
+            let mut options = settings.add.note_options(&target.deck_name);
+            options.allow_duplicate |= force;
+            note.options = Some(options);
+            Some(client.add_note(&note)?)
+        }
+    };
+    Ok(fired)
+}
+
+pub fn update_card(
+    client: &Client,
+    settings: &Settings,
+    note_id: i64,
+    mut fired: Fired,
+) -> Result<Fired, AnkiError> {
+    prepare_media(client, settings, &mut fired.card)?;
+    client.update_note_fields(note_id, &fired.card)?;
+    fired.note_id = Some(note_id);
+    Ok(fired)
+}
+
+/// Copies the media the card references and voices its audio, as configured.
+pub fn prepare_media(
+    client: &Client,
+    settings: &Settings,
+    card: &mut GuiAddCardsFields,
+) -> Result<(), AnkiError> {
+    if settings.add.copy_media {
+        media::copy_media(client, card)?;
+    }
+    if settings.tts.enabled {
+        tts::fill_audio(
+            client,
+            &settings.tts,
+            &settings.target.audio_guide_field,
+            card,
+        )?;
+    }
+    Ok(())
+}
+
+fn find_duplicates(
+    client: &Client,
+    settings: &Settings,
+    card: &GuiAddCardsFields,
+) -> Result<Vec<NoteInfo>, AnkiError> {
+    let Some(query) = duplicate_query(settings, card) else {
+        return Ok(vec![]);
+    };
+    let ids = client.find_notes(&query)?;
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+    client.notes_info(&ids)
+}
+
+/// Searches the target deck for notes with the same front, or `None` if the front is blank.
+pub fn duplicate_query(settings: &Settings, card: &GuiAddCardsFields) -> Option<String> {
+    let target = &settings.target;
+    let front = card.get(&target.front_field);
+    if front.trim().is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{} {}",
+        search_term(&format!("deck:{}", target.deck_name)),
+        search_term(&format!("{}:{}", target.front_field, front)),
+    ))
+}
+
+fn search_term(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        if matches!(c, '\\' | '"' | '*' | '_') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_query() {
+        let settings = Settings::default();
+        let mut card = GuiAddCardsFields::default();
+        assert_eq!(duplicate_query(&settings, &card), None);
+
+        card.set("Front", r#"噛[か]み "殺"_す*"#.into());
+        assert_eq!(
+            duplicate_query(&settings, &card).as_deref(),
+            Some(r#""deck:Immersion" "Front:噛[か]み \"殺\"\_す\*""#)
+        );
+    }
+}
@@ -0,0 +1,414 @@
+//! Anki's `base[reading]` furigana syntax.
+//!
+//! As in Anki, a base runs back to the previous space, tag or furigana, so
+//! `噛[か]み 殺[ころ]す` needs the space to keep `み` out of `殺`'s base. The space
+//! only marks the boundary and is dropped. Full-width brackets and spaces work
+//! like their ASCII counterparts, and `[sound:...]` is left as text.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Text(String),
+    /// An HTML tag, kept as is.
+    Tag(String),
+    Ruby {
+        base: String,
+        reading: String,
+    },
+}
+
+fn is_space(c: char) -> bool {
+    c == ' ' || c == '\u{3000}'
+}
+
+fn push_text(tokens: &mut Vec<Token>, s: &str) {
+    if s.is_empty() {
+        return;
+    }
+    match tokens.last_mut() {
+        Some(Token::Text(t)) => t.push_str(s),
+        _ => tokens.push(Token::Text(s.to_owned())),
+    }
+}
+
+/// Finds the bracket closing the reading that starts at `s`, returning the
+/// reading and the rest after the bracket.
+fn split_reading(s: &str) -> Option<(&str, &str)> {
+    let end = s.find([']', '］', '['])?;
+    if s[end..].starts_with('[') {
+        return None;
+    }
+    let close = s[end..].chars().next().unwrap();
+    Some((&s[..end], &s[end + close.len_utf8()..]))
+}
+
+pub fn parse(s: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    // the base so far, and the space before it if any
+    let mut base = String::new();
+    let mut space: Option<char> = None;
+    let flush = |tokens: &mut Vec<Token>, base: &mut String, space: &mut Option<char>| {
+        if let Some(c) = space.take() {
+            push_text(tokens, c.encode_utf8(&mut [0; 4]));
+        }
+        push_text(tokens, base);
+        base.clear();
+    };
+
+    let mut rest = s;
+    while let Some(c) = rest.chars().next() {
+        let after = &rest[c.len_utf8()..];
+        match c {
+            '<' if after.starts_with(|c: char| c.is_ascii_alphabetic() || c == '/' || c == '!') => {
+                flush(&mut tokens, &mut base, &mut space);
+                let end = rest.find('>').map_or(rest.len(), |i| i + 1);
+                tokens.push(Token::Tag(rest[..end].to_owned()));
+                rest = &rest[end..];
+                continue;
+            }
+            c if is_space(c) => {
+                flush(&mut tokens, &mut base, &mut space);
+                space = Some(c);
+            }
+            '[' | '［' => match split_reading(after) {
+                Some((reading, tail)) if !base.is_empty() && !reading.starts_with("sound:") => {
+                    tokens.push(Token::Ruby {
+                        base: std::mem::take(&mut base),
+                        reading: reading.to_owned(),
+                    });
+                    space = None;
+                    rest = tail;
+                    continue;
+                }
+                Some((_, tail)) => {
+                    base.push_str(&rest[..rest.len() - tail.len()]);
+                    flush(&mut tokens, &mut base, &mut space);
+                    rest = tail;
+                    continue;
+                }
+                None => base.push(c),
+            },
+            c => base.push(c),
+        }
+        rest = after;
+    }
+    flush(&mut tokens, &mut base, &mut space);
+    tokens
+}
+
+/// Writes tokens back in Anki's syntax, adding the spaces needed to keep the bases apart.
+pub fn format(tokens: &[Token]) -> String {
+    let mut out = String::new();
+    for token in tokens {
+        match token {
+            Token::Text(t) | Token::Tag(t) => out.push_str(t),
+            Token::Ruby { base, reading } => {
+                if !out.is_empty() && !out.ends_with(|c| is_space(c) || c == '>') {
+                    out.push(' ');
+                }
+                out.push_str(&format!("{base}[{reading}]"));
+            }
+        }
+    }
+    out
+}
+
+/// The text without readings, like Anki's `kanji:` filter.
+pub fn kanji(tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .map(|t| match t {
+            Token::Text(t) | Token::Tag(t) => t.as_str(),
+            Token::Ruby { base, .. } => base,
+        })
+        .collect()
+}
+
+/// The text with readings in place of their bases, like Anki's `kana:` filter.
+pub fn kana(tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .map(|t| match t {
+            Token::Text(t) | Token::Tag(t) => t.as_str(),
+            Token::Ruby { reading, .. } => reading,
+        })
+        .collect()
+}
+
+/// HTML ruby, like Anki's `furigana:` filter.
+pub fn ruby(tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .map(|t| match t {
+            Token::Text(t) | Token::Tag(t) => t.clone(),
+            Token::Ruby { base, reading } => {
+                format!("<ruby><rb>{base}</rb><rt>{reading}</rt></ruby>")
+            }
+        })
+        .collect()
+}
+
+/// The plain text to read aloud: the bases without tags, spaces or brackets.
+pub fn audio_guide(tokens: &[Token]) -> String {
+    guide(tokens, false)
+}
+
+/// Like [`audio_guide`], with the readings in place of their bases.
+pub fn reading_guide(tokens: &[Token]) -> String {
+    guide(tokens, true)
+}
+
+fn guide(tokens: &[Token], reading: bool) -> String {
+    tokens
+        .iter()
+        .filter_map(|t| match t {
+            Token::Text(t) => Some(t.as_str()),
+            Token::Ruby { reading: r, .. } if reading => Some(r),
+            Token::Ruby { base, .. } => Some(base),
+            Token::Tag(_) => None,
+        })
+        .flat_map(str::chars)
+        .filter(|&c| !is_space(c) && !"(){}（）".contains(c))
+        .collect()
+}
+
+/// Characters read together with the kanji around them, like `々` and the `ヶ` in `一ヶ月`.
+pub fn is_kanji(c: char) -> bool {
+    matches!(c,
+        '\u{4e00}'..='\u{9fff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{20000}'..='\u{2ebef}'
+        | '々' | '〆' | 'ヶ' | 'ヵ')
+}
+
+/// Folds katakana into hiragana, so either matches the other.
+fn fold_kana(c: char) -> char {
+    match c {
+        'ァ'..='ヶ' if !matches!(c, 'ヵ' | 'ヶ') => {
+            char::from_u32(c as u32 - 0x60).unwrap_or(c)
+        }
+        c => c,
+    }
+}
+
+/// Splits `reading` across the kanji of `word`, matching the kana between them
+/// (okurigana) against the reading, so `噛み殺す` read `かみころす` becomes
+/// `噛[か]み 殺[ころ]す` once formatted. Runs of kanji share one reading, since
+/// jukujikun like `今日` cannot be split. Falls back to the whole word over the
+/// whole reading when the kana do not line up.
+pub fn align(word: &str, reading: &str) -> Vec<Token> {
+    if reading.is_empty() || !word.chars().any(is_kanji) {
+        return vec![Token::Text(word.to_owned())];
+    }
+
+    // alternating runs of kanji and other characters
+    let mut runs: Vec<(bool, Vec<char>)> = vec![];
+    for c in word.chars() {
+        match runs.last_mut() {
+            Some((k, run)) if *k == is_kanji(c) => run.push(c),
+            _ => runs.push((is_kanji(c), vec![c])),
+        }
+    }
+    let chars: Vec<char> = reading.chars().collect();
+    let folded: Vec<char> = chars.iter().copied().map(fold_kana).collect();
+
+    let Some(lens) = match_runs(&runs, &folded) else {
+        return vec![Token::Ruby {
+            base: word.to_owned(),
+            reading: reading.to_owned(),
+        }];
+    };
+    let mut pos = 0;
+    runs.into_iter()
+        .zip(lens)
+        .map(|((kanji, run), len)| {
+            let base: String = run.into_iter().collect();
+            let r: String = chars[pos..pos + len].iter().collect();
+            pos += len;
+            if kanji {
+                Token::Ruby { base, reading: r }
+            } else {
+                Token::Text(base)
+            }
+        })
+        .collect()
+}
+
+/// The length of reading each run takes, with the earliest, shortest kanji readings first.
+fn match_runs(runs: &[(bool, Vec<char>)], reading: &[char]) -> Option<Vec<usize>> {
+    let Some(((kanji, run), rest)) = runs.split_first() else {
+        return reading.is_empty().then(Vec::new);
+    };
+    let lens: Vec<usize> = if *kanji {
+        (1..=reading.len()).collect()
+    } else {
+        let matches =
+            run.len() <= reading.len() && run.iter().zip(reading).all(|(&a, &b)| fold_kana(a) == b);
+        if matches {
+            vec![run.len()]
+        } else {
+            vec![]
+        }
+    };
+    lens.into_iter().find_map(|len| {
+        let mut tail = match_runs(rest, &reading[len..])?;
+        tail.insert(0, len);
+        Some(tail)
+    })
+}
+
+/// Aligns the readings of the tokens whose base mixes kanji and kana.
+pub fn align_tokens(tokens: Vec<Token>) -> Vec<Token> {
+    tokens
+        .into_iter()
+        .flat_map(|t| match t {
+            Token::Ruby { base, reading } => align(&base, &reading),
+            t => vec![t],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Token {
+        Token::Text(s.into())
+    }
+
+    fn ruby_token(base: &str, reading: &str) -> Token {
+        Token::Ruby {
+            base: base.into(),
+            reading: reading.into(),
+        }
+    }
+
+    #[test]
+    fn test_parse_okurigana() {
+        assert_eq!(
+            parse("噛[か]み 殺[ころ]す"),
+            vec![
+                ruby_token("噛", "か"),
+                text("み"),
+                ruby_token("殺", "ころ"),
+                text("す"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_word_boundary() {
+        // without the space, the base runs back to the previous furigana
+        assert_eq!(
+            parse("噛[か]み殺[ころ]す"),
+            vec![
+                ruby_token("噛", "か"),
+                ruby_token("み殺", "ころ"),
+                text("す")
+            ]
+        );
+        assert_eq!(parse("a < b[c]"), vec![text("a <"), ruby_token("b", "c")]);
+        // a space that does not start a base is kept
+        assert_eq!(
+            parse("to bite 噛[か]む"),
+            vec![text("to bite"), ruby_token("噛", "か"), text("む")]
+        );
+    }
+
+    #[test]
+    fn test_parse_html() {
+        assert_eq!(
+            parse("欠伸[あくび]を<b>噛[か]み</b>"),
+            vec![
+                ruby_token("欠伸", "あくび"),
+                text("を"),
+                Token::Tag("<b>".into()),
+                ruby_token("噛", "か"),
+                text("み"),
+                Token::Tag("</b>".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_full_width() {
+        assert_eq!(
+            parse("噛［か］み　殺［ころ］す"),
+            vec![
+                ruby_token("噛", "か"),
+                text("み"),
+                ruby_token("殺", "ころ"),
+                text("す"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_literal_brackets() {
+        assert_eq!(
+            parse("[sound:a.mp3] [x] a[b"),
+            vec![text("[sound:a.mp3] [x] a[b")]
+        );
+    }
+
+    #[test]
+    fn test_variants() {
+        let tokens = parse("<b>噛[か]み</b> 殺[ころ]す");
+        assert_eq!(kanji(&tokens), "<b>噛み</b>殺す");
+        assert_eq!(kana(&tokens), "<b>かみ</b>ころす");
+        assert_eq!(
+            ruby(&tokens),
+            "<b><ruby><rb>噛</rb><rt>か</rt></ruby>み</b><ruby><rb>殺</rb><rt>ころ</rt></ruby>す"
+        );
+        assert_eq!(audio_guide(&tokens), "噛み殺す");
+        assert_eq!(reading_guide(&tokens), "かみころす");
+        assert_eq!(format(&tokens), "<b>噛[か]み</b>殺[ころ]す");
+        assert_eq!(format(&parse("噛[か]み 殺[ころ]す")), "噛[か]み 殺[ころ]す");
+    }
+
+    /// Words whose reading is easy to misplace, with the expected furigana.
+    const CORPUS: &[(&str, &str, &str)] = &[
+        ("噛み殺す", "かみころす", "噛[か]み 殺[ころ]す"),
+        ("食べる", "たべる", "食[た]べる"),
+        ("欠伸", "あくび", "欠伸[あくび]"),
+        // jukujikun stay whole
+        ("今日", "きょう", "今日[きょう]"),
+        ("大人しい", "おとなしい", "大人[おとな]しい"),
+        ("一ヶ月", "いっかげつ", "一ヶ月[いっかげつ]"),
+        ("時々", "ときどき", "時々[ときどき]"),
+        // leading kana
+        ("お母さん", "おかあさん", "お 母[かあ]さん"),
+        ("ひと時", "ひととき", "ひと 時[とき]"),
+        ("すき焼き", "すきやき", "すき 焼[や]き"),
+        // repeated kana
+        ("見る見る", "みるみる", "見[み]る 見[み]る"),
+        ("好き好き", "すきずき", "好[す]き 好[ず]き"),
+        ("通り過ぎる", "とおりすぎる", "通[とお]り 過[す]ぎる"),
+        ("子供の頃", "こどものころ", "子供[こども]の 頃[ころ]"),
+        ("手当て", "てあて", "手当[てあ]て"),
+        // katakana in the word or the reading
+        ("サボる", "さぼる", "サボる"),
+        ("消しゴム", "けしごむ", "消[け]しゴム"),
+        ("噛み殺す", "カミコロス", "噛[カ]み 殺[コロ]す"),
+        // mismatched kana fall back to the whole word
+        ("噛み殺す", "かむ", "噛み殺す[かむ]"),
+        ("噛み殺す", "", "噛み殺す"),
+    ];
+
+    #[test]
+    fn test_align_corpus() {
+        for (word, reading, expected) in CORPUS {
+            assert_eq!(
+                format(&align(word, reading)),
+                *expected,
+                "{word} read {reading}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_align_tokens() {
+        let tokens = align_tokens(parse("欠伸[あくび]を 噛み殺す[かみころす]"));
+        assert_eq!(format(&tokens), "欠伸[あくび]を 噛[か]み 殺[ころ]す");
+    }
+}
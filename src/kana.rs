@@ -0,0 +1,286 @@
+//! Conversions between hiragana, katakana and romaji.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RomajiSystem {
+    /// `しゃ` is `sha`, and long vowels take a macron, as in `kōhī` and `tōkyō`.
+    #[default]
+    Hepburn,
+    /// `しゃ` is `sya`, and long vowels take a circumflex, as in `kôhî` and `tôkyô`.
+    Kunrei,
+}
+
+pub fn to_hiragana(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            'ァ'..='ヶ' | 'ヽ' | 'ヾ' => char::from_u32(c as u32 - 0x60).unwrap_or(c),
+            c => c,
+        })
+        .collect()
+}
+
+pub fn to_katakana(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            'ぁ'..='ゖ' | 'ゝ' | 'ゞ' => char::from_u32(c as u32 + 0x60).unwrap_or(c),
+            c => c,
+        })
+        .collect()
+}
+
+/// Syllables whose spelling differs between the systems: kana, Hepburn, Kunrei.
+const DIFFERING: &[(&str, &str, &str)] = &[
+    ("し", "shi", "si"),
+    ("ち", "chi", "ti"),
+    ("つ", "tsu", "tu"),
+    ("ふ", "fu", "hu"),
+    ("じ", "ji", "zi"),
+    ("ぢ", "ji", "zi"),
+    ("しゃ", "sha", "sya"),
+    ("しゅ", "shu", "syu"),
+    ("しょ", "sho", "syo"),
+    ("ちゃ", "cha", "tya"),
+    ("ちゅ", "chu", "tyu"),
+    ("ちょ", "cho", "tyo"),
+    ("じゃ", "ja", "zya"),
+    ("じゅ", "ju", "zyu"),
+    ("じょ", "jo", "zyo"),
+    ("ぢゃ", "ja", "zya"),
+    ("ぢゅ", "ju", "zyu"),
+    ("ぢょ", "jo", "zyo"),
+];
+
+/// Syllables spelled the same in both systems, besides the regular `ki`/`kya` ones.
+const SHARED: &[(&str, &str)] = &[
+    ("あ", "a"),
+    ("い", "i"),
+    ("う", "u"),
+    ("え", "e"),
+    ("お", "o"),
+    ("を", "o"),
+    ("ん", "n"),
+    ("ゔ", "vu"),
+    ("ぁ", "a"),
+    ("ぃ", "i"),
+    ("ぅ", "u"),
+    ("ぇ", "e"),
+    ("ぉ", "o"),
+    ("ゃ", "ya"),
+    ("ゅ", "yu"),
+    ("ょ", "yo"),
+    ("ゎ", "wa"),
+    ("や", "ya"),
+    ("ゆ", "yu"),
+    ("よ", "yo"),
+    ("わ", "wa"),
+    ("づ", "zu"),
+    // loanword sounds
+    ("ふぁ", "fa"),
+    ("ふぃ", "fi"),
+    ("ふぇ", "fe"),
+    ("ふぉ", "fo"),
+    ("てぃ", "ti"),
+    ("でぃ", "di"),
+    ("とぅ", "tu"),
+    ("どぅ", "du"),
+    ("うぃ", "wi"),
+    ("うぇ", "we"),
+    ("うぉ", "wo"),
+    ("ゔぁ", "va"),
+    ("ゔぃ", "vi"),
+    ("ゔぇ", "ve"),
+    ("ゔぉ", "vo"),
+    ("しぇ", "she"),
+    ("ちぇ", "che"),
+    ("じぇ", "je"),
+];
+
+/// Rows of the syllabary spelled as consonant plus vowel, with their `-ya` forms.
+const ROWS: &[(&str, &str)] = &[
+    ("かきくけこ", "k"),
+    ("がぎぐげご", "g"),
+    ("さ すせそ", "s"),
+    ("ざ ずぜぞ", "z"),
+    ("た  てと", "t"),
+    ("だ  でど", "d"),
+    ("なにぬねの", "n"),
+    ("はひ へほ", "h"),
+    ("ばびぶべぼ", "b"),
+    ("ぱぴぷぺぽ", "p"),
+    ("まみむめも", "m"),
+    ("らりるれろ", "r"),
+];
+
+fn syllable(kana: &str, system: RomajiSystem) -> Option<String> {
+    if let Some((_, hepburn, kunrei)) = DIFFERING.iter().find(|(k, ..)| *k == kana) {
+        return Some(
+            match system {
+                RomajiSystem::Hepburn => hepburn,
+                RomajiSystem::Kunrei => kunrei,
+            }
+            .to_string(),
+        );
+    }
+    if let Some((_, r)) = SHARED.iter().find(|(k, _)| *k == kana) {
+        return Some(r.to_string());
+    }
+    let mut chars = kana.chars();
+    let first = chars.next()?;
+    let small = chars.next();
+    if chars.next().is_some() {
+        return None;
+    }
+    // the gaps in the rows are spelled irregularly
+    if first == ' ' {
+        return None;
+    }
+    let (row, consonant) = ROWS.iter().find(|(row, _)| row.contains(first))?;
+    let vowel = ["a", "i", "u", "e", "o"][row.chars().position(|c| c == first)?];
+    match small {
+        None => Some(format!("{consonant}{vowel}")),
+        // only the i column takes a small ya, yu or yo
+        Some(s) if vowel == "i" => {
+            let y = ["ゃ", "ゅ", "ょ"].iter().position(|y| y.starts_with(s))?;
+            Some(format!("{consonant}y{}", ["a", "u", "o"][y]))
+        }
+        Some(_) => None,
+    }
+}
+
+fn lengthen(vowel: char, system: RomajiSystem) -> Option<char> {
+    let (plain, long) = match system {
+        RomajiSystem::Hepburn => ("aiueo", "āīūēō"),
+        RomajiSystem::Kunrei => ("aiueo", "âîûêô"),
+    };
+    let i = plain.chars().position(|c| c == vowel)?;
+    long.chars().nth(i)
+}
+
+/// Whether the vowel kana `c` lengthens the vowel spelled before it, as `う`
+/// does in `とう` and `お` does in `とお`. `えい` and `いい` are spelled out.
+fn is_long(c: char, prev: Option<char>) -> bool {
+    matches!((c, prev), ('う', Some('o' | 'u')) | ('お', Some('o')))
+}
+
+/// Spells kana in romaji. Other characters, like kanji, are kept as they are.
+///
+/// Long vowels are merged without knowing where words start, so `おもう` is
+/// spelled `omō`.
+pub fn to_romaji(s: &str, system: RomajiSystem) -> String {
+    let chars: Vec<char> = to_hiragana(s).chars().collect();
+    let mut out = String::new();
+    // a small tsu doubles the consonant that follows it
+    let mut geminate = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == 'っ' {
+            geminate = true;
+            i += 1;
+            continue;
+        }
+        if c == 'ー' {
+            match out.pop() {
+                Some(v) => out.push(lengthen(v, system).unwrap_or(v)),
+                None => out.push(c),
+            }
+            i += 1;
+            continue;
+        }
+
+        let two: String = chars[i..chars.len().min(i + 2)].iter().collect();
+        let (romaji, len) = match syllable(&two, system).filter(|_| two.chars().count() == 2) {
+            Some(r) => (r, 2),
+            None if !geminate && is_long(c, out.chars().last()) => {
+                let v = out.pop().unwrap_or_default();
+                out.push(lengthen(v, system).unwrap_or(v));
+                i += 1;
+                continue;
+            }
+            None => match syllable(&c.to_string(), system) {
+                Some(r) => (r, 1),
+                None => {
+                    out.push(c);
+                    geminate = false;
+                    i += 1;
+                    continue;
+                }
+            },
+        };
+        if geminate {
+            if system == RomajiSystem::Hepburn && romaji.starts_with("ch") {
+                out.push('t');
+            } else if let Some(first) = romaji.chars().next().filter(|c| !"aiueon".contains(*c)) {
+                out.push(first);
+            }
+            geminate = false;
+        }
+        // ん before a vowel or y is marked, so `kan'i` is not read `kani`
+        if out.ends_with('n')
+            && chars.get(i.wrapping_sub(1)) == Some(&'ん')
+            && romaji.starts_with(['a', 'i', 'u', 'e', 'o', 'y'])
+        {
+            out.push('\'');
+        }
+        out.push_str(&romaji);
+        i += len;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kana_scripts() {
+        let cases = [
+            ("かみころす", "カミコロス"),
+            ("ゔぁいおりん", "ヴァイオリン"),
+            ("いすゞ", "イスヾ"),
+            ("こーひー", "コーヒー"),
+        ];
+        for (hiragana, katakana) in cases {
+            assert_eq!(to_katakana(hiragana), katakana);
+            assert_eq!(to_hiragana(katakana), hiragana);
+        }
+        assert_eq!(to_hiragana("噛みコロス"), "噛みころす");
+    }
+
+    #[test]
+    fn test_romaji() {
+        let cases = [
+            ("かみころす", "kamikorosu", "kamikorosu"),
+            ("しゃしん", "shashin", "syasin"),
+            ("ちゃわん", "chawan", "tyawan"),
+            ("つづく", "tsuzuku", "tuzuku"),
+            ("ふじさん", "fujisan", "huzisan"),
+            ("じゅうよう", "jūyō", "zyûyô"),
+            ("きょうと", "kyōto", "kyôto"),
+            ("とうきょう", "tōkyō", "tôkyô"),
+            ("おおさか", "ōsaka", "ôsaka"),
+            ("ゆうき", "yūki", "yûki"),
+            ("がっこう", "gakkō", "gakkô"),
+            ("せんせい", "sensei", "sensei"),
+            ("おにいさん", "oniisan", "oniisan"),
+            ("ウォーク", "wōku", "wôku"),
+            ("まっちゃ", "matcha", "mattya"),
+            ("ざっし", "zasshi", "zassi"),
+            ("あっ", "a", "a"),
+            ("かんい", "kan'i", "kan'i"),
+            ("こんや", "kon'ya", "kon'ya"),
+            ("しんぶん", "shinbun", "sinbun"),
+            ("コーヒー", "kōhī", "kôhî"),
+            ("ラーメン", "rāmen", "râmen"),
+            ("パーティー", "pātī", "pâtî"),
+            ("ヴァイオリン", "vaiorin", "vaiorin"),
+            ("フォーク", "fōku", "fôku"),
+            ("噛みころす", "噛mikorosu", "噛mikorosu"),
+        ];
+        for (kana, hepburn, kunrei) in cases {
+            assert_eq!(to_romaji(kana, RomajiSystem::Hepburn), hepburn, "{kana}");
+            assert_eq!(to_romaji(kana, RomajiSystem::Kunrei), kunrei, "{kana}");
+        }
+    }
+}
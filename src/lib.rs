@@ -0,0 +1,16 @@
+pub mod ankiconnect;
+pub mod batch;
+pub mod card;
+pub mod cli;
+pub mod config;
+pub mod dictionary;
+pub mod error;
+pub mod fire;
+pub mod furigana;
+pub mod kana;
+pub mod media;
+pub mod pitch;
+pub mod preview;
+pub mod sanitize;
+pub mod template;
+pub mod tts;
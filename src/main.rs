@@ -1,79 +1,96 @@
-use std::{mem, thread};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    mem,
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
 
-use regex::Regex;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use anki_copy_card_egui::{
+    ankiconnect::{Client, NoteInfo},
+    batch::{self, BatchRow, ColumnMapping, RowStatus, Table},
+    card::{self, derive_audio_guide, MappingProfile, SourceCard, DEFAULT_TARGET_FIELDS},
+    cli,
+    config::{AccentFormat, AddMode, AudioGuideStyle, Connection, Overrides, Settings},
+    dictionary::{Dictionary, Entry},
+    error::AnkiError,
+    fire::{self, FireOutcome, Fired, HistoryEntry},
+    kana::RomajiSystem,
+    preview,
+    sanitize::Policy,
+    template::{self, Template},
+};
+use serde::{Deserialize, Serialize};
 use tap::Tap;
 
-#[derive(Serialize)]
-struct RB {
-    action: String,
-    version: u8,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    params: Option<serde_json::Value>,
-}
-
-fn anki_request<T: DeserializeOwned>(
-    action: String,
-    params: Option<serde_json::Value>,
-) -> anyhow::Result<T> {
-    let url = format!("http://localhost:8765");
-    let body = RB {
-        action,
-        version: 6,
-        params,
-    };
-
-    let data: T = ureq::post(&url).send_json(body)?.into_json()?;
-
-    Ok(data)
-}
+const SESSION_KEY: &str = "session";
+const HISTORY_LIMIT: usize = 500;
 
-#[allow(unused)]
-#[derive(Debug, Deserialize)]
-struct Field {
-    value: String,
-    order: i64,
+/// What is restored on the next launch, besides [`Settings`].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+struct Session {
+    follow_front: bool,
+    maintain_prev: bool,
+    fired: i64,
+    prev_card: Option<Fired>,
+    last_source: Option<SourceCard>,
+    draft: BTreeMap<String, String>,
+    history: Vec<HistoryEntry>,
+    watch: bool,
+    watch_interval: f32,
 }
 
-#[allow(unused)]
-#[derive(Debug, Deserialize)]
-struct GuiCurrentCard {
-    error: Option<serde_json::Value>,
-    result: Option<GuiCurrentCardResult>,
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            follow_front: true,
+            maintain_prev: false,
+            fired: 0,
+            prev_card: None,
+            last_source: None,
+            draft: BTreeMap::new(),
+            history: vec![],
+            watch: false,
+            watch_interval: 1.,
+        }
+    }
 }
 
-#[allow(unused)]
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct GuiCurrentCardResult {
-    deck_name: String,
-    fields: GuiCurrentCardFields,
+enum HistoryAction {
+    Browse(String),
+    Edit(i64),
+    Delete(i64),
 }
 
-#[allow(unused)]
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "PascalCase")]
-struct GuiCurrentCardFields {
-    kanji: Field,
-    kana: Field,
-    sentence_front: Field,
-    sentence_back: Field,
-    picture: Field,
-    kanken_audio: Field,
-    kanken_level: Field,
-    meaning: Field,
-    diagram: Field,
+#[derive(Debug)]
+enum Fetched {
+    Decks(Vec<String>),
+    Models(Vec<String>),
+    ModelFields(String, Vec<String>),
+    NoteDeleted(i64),
+    /// Kept apart from other failures, which would otherwise flood the status on every refresh.
+    Preview(Result<Fired, AnkiError>),
+    /// The card being reviewed, polled while watching. Its failures are kept apart too.
+    Watched(Result<Option<SourceCard>, AnkiError>),
+    /// The batch rows, once checked for duplicates, and why checking failed if it did.
+    BatchChecked(Vec<BatchRow>, Option<AnkiError>),
+    BatchSubmitted(Vec<BatchRow>),
+    /// The imported or reopened dictionary, and why importing failed if it did.
+    Dictionary(Result<Dictionary, String>),
+    Done,
 }
 
-#[derive(Debug, Serialize, Clone)]
-#[serde(rename_all = "PascalCase")]
-struct GuiAddCardsFields {
-    front: String,
-    back: String,
-    #[serde(rename = "Back Paragraph")]
-    back_paragraph: String,
-    audio_guide: String,
-    audio: String,
+/// The batch import window, which adds a note for each row of a CSV or TSV file.
+#[derive(Debug, Default)]
+struct Batch {
+    open: bool,
+    path: String,
+    table: Option<Table>,
+    mapping: ColumnMapping,
+    rows: Vec<BatchRow>,
+    busy: bool,
+    error: Option<String>,
 }
 
 fn setup_fonts(ctx: &egui::Context) {
@@ -86,16 +103,14 @@ fn setup_fonts(ctx: &egui::Context) {
         NOTO_JP.into(),
         egui::FontData::from_static(include_bytes!(
             "../assets/NotoSansJP/NotoSansJP-VariableFont_wght.ttf"
-        ))
-        .into(),
+        )),
     );
 
     fonts.font_data.insert(
         NOTO_TH.into(),
         egui::FontData::from_static(include_bytes!(
             "../assets/NotoSansThai/NotoSansThai-VariableFont_wdth,wght.ttf"
-        ))
-        .into(),
+        )),
     );
 
     fonts
@@ -114,29 +129,75 @@ fn setup_fonts(ctx: &egui::Context) {
 #[derive(Debug)]
 pub struct AppState {
     r: AppStateResistReset,
-    front: String,
-    audio_guide: String,
+    /// Values typed by the user, which take precedence over the derived ones.
+    custom: BTreeMap<String, String>,
     follow_front: bool,
-    back: String,
+}
+
+/// The dictionary panel, which inserts the picked senses into the back.
+#[derive(Debug, Default)]
+struct Lookup {
+    dictionary: Option<Dictionary>,
+    source: String,
+    importing: bool,
+    error: Option<String>,
+    word: String,
+    entries: Vec<Entry>,
+    /// The entry and sense indices to insert.
+    picked: BTreeSet<(usize, usize)>,
 }
 
 #[derive(Debug)]
 pub struct AppStateResistReset {
-    req_complete: crossbeam::channel::Receiver<GuiAddCardsFields>,
-    req_complete_s: crossbeam::channel::Sender<GuiAddCardsFields>,
+    req_complete: crossbeam::channel::Receiver<Result<FireOutcome, AnkiError>>,
+    req_complete_s: crossbeam::channel::Sender<Result<FireOutcome, AnkiError>>,
+    duplicates: Option<(Fired, Vec<NoteInfo>)>,
+    fetched: crossbeam::channel::Receiver<Result<Fetched, AnkiError>>,
+    fetched_s: crossbeam::channel::Sender<Result<Fetched, AnkiError>>,
+    decks: Vec<String>,
+    models: Vec<String>,
+    target_fields: Vec<String>,
+    new_tag: String,
+    in_flight: usize,
+    status: Option<Result<String, AnkiError>>,
     fired: i64,
-    prev_card: Option<GuiAddCardsFields>,
+    prev_card: Option<Fired>,
     maintain_prev: bool,
+    settings: Settings,
+    overrides: Overrides,
+    settings_status: Option<String>,
+    /// The settings as last loaded or saved, so unchanged ones are not written back.
+    saved_settings: Settings,
+    /// Why the settings file could not be loaded. Nothing is saved automatically
+    /// until the user saves explicitly, which would overwrite the file.
+    settings_error: Option<String>,
+    last_source: Option<SourceCard>,
+    profile_model: String,
+    history: Vec<HistoryEntry>,
+    preview: Option<Result<Fired, AnkiError>>,
+    preview_auto: bool,
+    preview_interval: f32,
+    preview_at: Option<Instant>,
+    preview_pending: bool,
+    batch: Batch,
+    watch: bool,
+    watch_interval: f32,
+    watch_at: Option<Instant>,
+    watch_pending: bool,
+    /// The card being reviewed when last polled, and why polling failed if it did.
+    watched: Option<Result<SourceCard, AnkiError>>,
+    /// The last card watched successfully, so a failed poll in between does not
+    /// pre-fill the same card again.
+    last_watched: Option<SourceCard>,
+    lookup: Lookup,
 }
 
 impl Default for AppState {
     fn default() -> Self {
         Self {
             r: Default::default(),
-            front: String::new(),
-            audio_guide: String::new(),
+            custom: BTreeMap::new(),
             follow_front: true,
-            back: String::new(),
         }
     }
 }
@@ -144,21 +205,141 @@ impl Default for AppState {
 impl Default for AppStateResistReset {
     fn default() -> Self {
         let (req_complete_s, req_complete) = crossbeam::channel::unbounded();
+        let (fetched_s, fetched) = crossbeam::channel::unbounded();
         Self {
             req_complete,
             req_complete_s,
+            duplicates: None,
+            fetched,
+            fetched_s,
+            decks: vec![],
+            models: vec![],
+            target_fields: DEFAULT_TARGET_FIELDS.map(String::from).to_vec(),
+            new_tag: String::new(),
+            in_flight: 0,
+            status: None,
             fired: 0,
             prev_card: None,
             maintain_prev: false,
+            settings: Default::default(),
+            overrides: Default::default(),
+            settings_status: None,
+            saved_settings: Default::default(),
+            settings_error: None,
+            last_source: None,
+            profile_model: String::new(),
+            history: vec![],
+            preview: None,
+            preview_auto: false,
+            preview_interval: 2.,
+            preview_at: None,
+            preview_pending: false,
+            batch: Batch::default(),
+            watch: false,
+            watch_interval: 1.,
+            watch_at: None,
+            watch_pending: false,
+            watched: None,
+            last_watched: None,
+            lookup: Lookup::default(),
         }
     }
 }
 
 impl AppState {
-    fn new(cc: &eframe::CreationContext<'_>) -> Self {
+    fn new(cc: &eframe::CreationContext<'_>, overrides: Overrides) -> Self {
         setup_fonts(&cc.egui_ctx);
 
-        Default::default()
+        Self::default().tap_mut(|s| {
+            match Settings::load() {
+                Ok(settings) => {
+                    s.r.saved_settings = settings.clone();
+                    s.r.settings = settings;
+                }
+                Err(e) => s.r.settings_error = Some(e.to_string()),
+            }
+            s.r.overrides = overrides;
+            if let Some(session) = cc
+                .storage
+                .and_then(|st| eframe::get_value::<Session>(st, SESSION_KEY))
+            {
+                s.restore(session);
+            }
+            s.refresh_collections(cc.egui_ctx.clone());
+            s.open_dictionary(cc.egui_ctx.clone());
+        })
+    }
+
+    fn restore(&mut self, session: Session) {
+        self.follow_front = session.follow_front;
+        self.custom = session.draft;
+        self.r.maintain_prev = session.maintain_prev;
+        self.r.fired = session.fired;
+        self.r.prev_card = session.prev_card;
+        if let Some(source) = &session.last_source {
+            self.r.profile_model.clone_from(&source.model_name);
+        }
+        self.r.last_source = session.last_source;
+        self.r.history = session.history;
+        self.r.watch = session.watch;
+        self.r.watch_interval = session.watch_interval;
+    }
+
+    fn session(&self) -> Session {
+        Session {
+            follow_front: self.follow_front,
+            maintain_prev: self.r.maintain_prev,
+            fired: self.r.fired,
+            prev_card: self.r.prev_card.clone(),
+            last_source: self.r.last_source.clone(),
+            draft: self
+                .custom
+                .iter()
+                .filter(|(_, v)| !v.is_empty())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            history: self.r.history.clone(),
+            watch: self.r.watch,
+            watch_interval: self.r.watch_interval,
+        }
+    }
+
+    fn fetch(
+        &self,
+        c: egui::Context,
+        f: impl FnOnce(&Client) -> Result<Fetched, AnkiError> + Send + 'static,
+    ) {
+        let client = Client::new(self.connection());
+        let sender = self.r.fetched_s.clone();
+        _ = thread::spawn(move || {
+            _ = sender.send(f(&client));
+            c.request_repaint();
+        });
+    }
+
+    fn refresh_collections(&self, c: egui::Context) {
+        self.fetch(c.clone(), |client| client.deck_names().map(Fetched::Decks));
+        self.fetch(c.clone(), |client| {
+            client.model_names().map(Fetched::Models)
+        });
+        self.refresh_target_fields(c);
+    }
+
+    fn refresh_target_fields(&self, c: egui::Context) {
+        let model = self.r.settings.target.model_name.clone();
+        self.fetch(c, move |client| {
+            client
+                .model_field_names(&model)
+                .map(|fields| Fetched::ModelFields(model, fields))
+        });
+    }
+
+    fn connection(&self) -> Connection {
+        self.r
+            .settings
+            .connection
+            .clone()
+            .tap_mut(|c| self.r.overrides.apply(c))
     }
 
     fn reset(&mut self) -> &mut Self {
@@ -170,168 +351,1397 @@ impl AppState {
     }
 
     fn audio_guide_follow(&mut self) -> &mut Self {
-        self.audio_guide = create_audio_guide(&self.front);
+        let target = &self.r.settings.target;
+        let front = self
+            .custom
+            .get(&target.front_field)
+            .map_or("", String::as_str);
+        let audio_guide = derive_audio_guide(front, target.audio_guide_style);
+        self.custom
+            .insert(target.audio_guide_field.clone(), audio_guide);
         self
     }
 
-    fn fire(&self, c: egui::Context) {
-        let front = self.front.trim().to_owned();
-        let audio_guide = self.audio_guide.trim().to_owned();
-        let back = self.back.trim().replace('\n', "<br />");
+    fn refresh_preview(&mut self, c: egui::Context) {
+        let custom = self.custom.clone();
+        let fields = self.r.target_fields.clone();
+        let settings = self.r.settings.clone();
+        let prev_card = self.r.prev_card.clone();
+        self.r.preview_at = Some(Instant::now());
+        self.r.preview_pending = true;
+        self.fetch(c, move |client| {
+            Ok(Fetched::Preview(fire::compose(
+                client, &settings, &fields, prev_card, custom,
+            )))
+        });
+    }
 
+    /// Refreshes the preview when it is due, whether or not it is shown.
+    fn auto_refresh_preview(&mut self, ctx: &egui::Context) {
+        if !self.r.preview_auto || self.r.preview_pending {
+            return;
+        }
+        let interval = Duration::from_secs_f32(self.r.preview_interval);
+        match self.r.preview_at {
+            Some(at) if at.elapsed() < interval => {
+                ctx.request_repaint_after(interval - at.elapsed());
+            }
+            _ => self.refresh_preview(ctx.clone()),
+        }
+    }
+
+    /// Polls the reviewer when it is due, whether or not the watched card is shown.
+    fn auto_poll_current_card(&mut self, ctx: &egui::Context) {
+        if !self.r.watch || self.r.watch_pending {
+            return;
+        }
+        let interval = Duration::from_secs_f32(self.r.watch_interval);
+        match self.r.watch_at {
+            Some(at) if at.elapsed() < interval => {
+                ctx.request_repaint_after(interval - at.elapsed());
+            }
+            _ => self.poll_current_card(ctx.clone()),
+        }
+    }
+
+    fn poll_current_card(&mut self, c: egui::Context) {
+        self.r.watch_at = Some(Instant::now());
+        self.r.watch_pending = true;
+        self.fetch(c, |client| {
+            Ok(Fetched::Watched(client.gui_current_card::<SourceCard>()))
+        });
+    }
+
+    /// Pre-fills the front, back and audio guide from a newly reviewed card.
+    fn on_watched(&mut self, card: SourceCard) {
+        if self.r.last_watched.as_ref() == Some(&card) {
+            self.r.watched = Some(Ok(card));
+            return;
+        }
+        let settings = &self.r.settings;
+        match card::compose_card(
+            &BTreeMap::new(),
+            None,
+            Some(&card),
+            &settings.profiles,
+            &self.r.target_fields,
+        ) {
+            Ok(composed) => {
+                let target = &settings.target;
+                for field in [
+                    &target.front_field,
+                    &target.back_field,
+                    &target.audio_guide_field,
+                ] {
+                    if self.r.target_fields.contains(field) {
+                        self.custom
+                            .insert(field.clone(), composed.get(field).to_owned());
+                    }
+                }
+                if self.follow_front {
+                    self.audio_guide_follow();
+                }
+            }
+            Err(e) => self.r.status = Some(Err(e)),
+        }
+        if self.r.profile_model.is_empty() {
+            self.r.profile_model.clone_from(&card.model_name);
+        }
+        self.r.last_source = Some(card.clone());
+        self.r.last_watched = Some(card.clone());
+        self.r.watched = Some(Ok(card));
+    }
+
+    fn fire(&mut self, c: egui::Context) {
+        let custom = self.custom.clone();
+
+        let fields = self.r.target_fields.clone();
+        let settings = self.r.settings.clone();
         let prev_card = self.r.prev_card.clone();
+        self.send(c, move |client| {
+            fire::fire_card(client, &settings, &fields, prev_card, custom)
+        });
+    }
+
+    fn on_fired(&mut self, fired: Fired, updated: bool) {
+        if let Some(source) = &fired.source {
+            if self.r.profile_model.is_empty() {
+                self.r.profile_model.clone_from(&source.model_name);
+            }
+            self.r.last_source = Some(source.clone());
+        }
+        self.r.fired += 1;
+        let front = fired.card.get(&self.r.settings.target.front_field);
+        self.r.status = Some(Ok(match (updated, fired.note_id) {
+            (true, Some(id)) => format!("Updated note {id}: {front}"),
+            (_, Some(id)) => format!("Added note {id}: {front}"),
+            (_, None) => format!("Fired: {front}"),
+        }));
+        self.r.history.push(HistoryEntry {
+            at: chrono::Local::now(),
+            fired: fired.clone(),
+            updated,
+            deleted: false,
+        });
+        let excess = self.r.history.len().saturating_sub(HISTORY_LIMIT);
+        self.r.history.drain(..excess);
+        self.reset();
+        self.r.prev_card = self.r.maintain_prev.then_some(fired);
+    }
+
+    fn load_batch(&mut self, c: egui::Context) {
+        let batch = &mut self.r.batch;
+        match Table::load(batch.path.trim().as_ref()) {
+            Ok(table) => {
+                batch.mapping = batch::default_mapping(&table.headers, &self.r.target_fields);
+                batch.table = Some(table);
+                batch.error = None;
+                self.check_batch(c);
+            }
+            Err(e) => batch.error = Some(format!("Failed to load: {e}")),
+        }
+    }
+
+    fn check_batch(&mut self, c: egui::Context) {
+        let batch = &mut self.r.batch;
+        let Some(table) = &batch.table else {
+            return;
+        };
+        let mut rows = batch::build_rows(
+            table,
+            &batch.mapping,
+            &self.r.settings.target,
+            &self.r.target_fields,
+        );
+        batch.busy = true;
+        let settings = self.r.settings.clone();
+        self.fetch(c, move |client| {
+            let res = batch::check_duplicates(client, &settings, &mut rows);
+            Ok(Fetched::BatchChecked(rows, res.err()))
+        });
+    }
+
+    fn submit_batch(&mut self, c: egui::Context) {
+        let mut rows = self.r.batch.rows.clone();
+        self.r.batch.busy = true;
+        let settings = self.r.settings.clone();
+        self.fetch(c, move |client| {
+            batch::submit(client, &settings, &mut rows);
+            Ok(Fetched::BatchSubmitted(rows))
+        });
+    }
+
+    fn send(
+        &mut self,
+        c: egui::Context,
+        f: impl FnOnce(&Client) -> Result<FireOutcome, AnkiError> + Send + 'static,
+    ) {
+        let client = Client::new(self.connection());
         let sender = self.r.req_complete_s.clone();
+        self.r.in_flight += 1;
         _ = thread::spawn(move || {
-            let new_card = if let Some(mut p) = prev_card {
-                if !front.is_empty() {
-                    p.front = front;
+            _ = sender.send(f(&client));
+            c.request_repaint();
+        });
+    }
+}
+
+impl AppState {
+    fn field_row(&mut self, ui: &mut egui::Ui, field: &str) {
+        let target = &self.r.settings.target;
+        let is_front = field == target.front_field;
+        let is_audio_guide = field == target.audio_guide_field;
+        let value = self.custom.entry(field.to_owned()).or_default();
+
+        ui.label(format!("Custom {field}:"));
+        if is_front {
+            if ui
+                .add(egui::TextEdit::singleline(value).hint_text("噛[か]み 殺[ころ]す"))
+                .changed()
+                && self.follow_front
+            {
+                self.audio_guide_follow();
+            }
+        } else if is_audio_guide {
+            let follow = &mut self.follow_front;
+            let follow_changed = ui
+                .vertical(|ui| {
+                    ui.add(egui::TextEdit::singleline(value).hint_text("噛み殺す"));
+                    ui.checkbox(follow, "Follow Front").changed()
+                })
+                .inner;
+            if follow_changed && self.follow_front {
+                self.audio_guide_follow();
+            }
+        } else {
+            ui.add(
+                egui::TextEdit::multiline(value)
+                    .desired_rows(1)
+                    .hint_text("Derived from source"),
+            );
+        }
+    }
+
+    fn preview_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui.button("Refresh").clicked() {
+                self.refresh_preview(ui.ctx().clone());
+            }
+            ui.checkbox(&mut self.r.preview_auto, "Every");
+            ui.add(
+                egui::DragValue::new(&mut self.r.preview_interval)
+                    .range(0.5..=60.)
+                    .speed(0.1)
+                    .suffix(" s"),
+            );
+        });
+
+        let preview = match &self.r.preview {
+            None => {
+                ui.label("Nothing to preview yet.");
+                return;
+            }
+            Some(Err(e)) => {
+                ui.colored_label(ui.visuals().error_fg_color, e.to_string());
+                return;
+            }
+            Some(Ok(preview)) => preview,
+        };
+        if let Some(source) = &preview.source {
+            ui.label(format!(
+                "From: {} ({})",
+                source.deck_name, source.model_name
+            ));
+        }
+        let front_field = &self.r.settings.target.front_field;
+        egui::Grid::new("preview-grid")
+            .spacing([4.0, 4.0])
+            .num_columns(2)
+            .striped(true)
+            .show(ui, |ui| {
+                for field in &self.r.target_fields {
+                    let value = preview.card.get(field);
+                    ui.label(format!("{field}:"));
+                    if field == front_field {
+                        preview::ruby_label(ui, value);
+                    } else {
+                        ui.label(preview::html_to_text(value)).on_hover_text(value);
+                    }
+                    ui.end_row();
                 }
-                if !back.is_empty() {
-                    p.back = back;
+            });
+    }
+
+    fn watch_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui
+                .checkbox(&mut self.r.watch, "Watch the reviewer every")
+                .changed()
+                && !self.r.watch
+            {
+                self.r.watched = None;
+                self.r.last_watched = None;
+            }
+            ui.add(
+                egui::DragValue::new(&mut self.r.watch_interval)
+                    .range(0.5..=60.)
+                    .speed(0.1)
+                    .suffix(" s"),
+            );
+        });
+
+        let card = match &self.r.watched {
+            None => {
+                ui.label("Not watching.");
+                return;
+            }
+            Some(Err(e)) => {
+                ui.colored_label(ui.visuals().error_fg_color, e.to_string());
+                return;
+            }
+            Some(Ok(card)) => card,
+        };
+        ui.label(format!("{} ({})", card.deck_name, card.model_name));
+        egui::Grid::new("watched-grid")
+            .spacing([4.0, 4.0])
+            .num_columns(2)
+            .striped(true)
+            .show(ui, |ui| {
+                let names = card.field_names();
+                // the KanKen fields first, or every field for other note types
+                let shown: Vec<&str> = ["Kanji", "Kana", "Meaning", "SentenceBack", "Sentence"]
+                    .into_iter()
+                    .filter(|n| names.contains(n))
+                    .collect();
+                for name in if shown.is_empty() { names } else { shown } {
+                    let value = &card.fields[name].value;
+                    ui.label(format!("{name}:"));
+                    ui.label(preview::html_to_text(value)).on_hover_text(value);
+                    ui.end_row();
                 }
-                if !audio_guide.is_empty() {
-                    p.audio_guide = audio_guide;
+            });
+    }
+
+    fn history_ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.heading("History");
+            if ui.small_button("Clear").clicked() {
+                self.r.history.clear();
+            }
+        });
+
+        let front_field = self.r.settings.target.front_field.clone();
+        let mut action = None;
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for entry in self.r.history.iter().rev() {
+                let fired = &entry.fired;
+                let mut title = format!(
+                    "{} {}",
+                    entry.at.format("%m-%d %H:%M"),
+                    fired.card.get(&front_field)
+                );
+                if entry.deleted {
+                    title = format!("{title} (deleted)");
+                } else if entry.updated {
+                    title = format!("{title} (updated)");
                 }
-                p
-            } else {
-                let Ok(ccard) = anki_request::<GuiCurrentCard>("guiCurrentCard".into(), None)
-                else {
-                    return;
-                };
-                let Some(data) = ccard.result else {
+                egui::CollapsingHeader::new(title)
+                    .id_source(("history", entry.at.timestamp_micros()))
+                    .show(ui, |ui| {
+                        if let Some(source) = &fired.source {
+                            ui.label(format!(
+                                "From: {} ({})",
+                                source.deck_name, source.model_name
+                            ));
+                        }
+                        match fired.note_id {
+                            Some(id) => ui.label(format!("Note ID: {id}")),
+                            None => ui.label("Note ID unknown"),
+                        };
+                        egui::Grid::new(("history-fields", entry.at.timestamp_micros()))
+                            .num_columns(2)
+                            .show(ui, |ui| {
+                                for (name, value) in &fired.card.0 {
+                                    ui.label(format!("{name}:"));
+                                    ui.label(value);
+                                    ui.end_row();
+                                }
+                            });
+
+                        ui.add_enabled_ui(!entry.deleted, |ui| {
+                            ui.horizontal(|ui| {
+                                let query = match fired.note_id {
+                                    Some(id) => Some(format!("nid:{id}")),
+                                    None => fire::duplicate_query(&self.r.settings, &fired.card),
+                                };
+                                if let Some(query) = query {
+                                    if ui.button("Browse").clicked() {
+                                        action = Some(HistoryAction::Browse(query));
+                                    }
+                                }
+                                if let Some(id) = fired.note_id {
+                                    if ui.button("Edit").clicked() {
+                                        action = Some(HistoryAction::Edit(id));
+                                    }
+                                    if !entry.updated && ui.button("Undo").clicked() {
+                                        action = Some(HistoryAction::Delete(id));
+                                    }
+                                }
+                            });
+                        });
+                    });
+            }
+        });
+
+        let c = ui.ctx().clone();
+        match action {
+            None => {}
+            Some(HistoryAction::Browse(query)) => self.fetch(c, move |client| {
+                client.gui_browse(&query).map(|_| Fetched::Done)
+            }),
+            Some(HistoryAction::Edit(id)) => self.fetch(c, move |client| {
+                ensure_note_exists(client, id)?;
+                client.gui_edit_note(id).map(|_| Fetched::Done)
+            }),
+            Some(HistoryAction::Delete(id)) => self.fetch(c, move |client| {
+                ensure_note_exists(client, id)?;
+                client.delete_notes(&[id]).map(|_| Fetched::NoteDeleted(id))
+            }),
+        }
+    }
+
+    fn duplicates_ui(&mut self, ctx: &egui::Context) {
+        let Some((fired, existing)) = &self.r.duplicates else {
+            return;
+        };
+
+        enum Choice {
+            Cancel,
+            AddAnyway,
+            Update(i64),
+        }
+        let mut choice = None;
+        egui::Window::new("Existing Notes Found")
+            .collapsible(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0., 0.])
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "{} note(s) in {} already have this front:",
+                    existing.len(),
+                    self.r.settings.target.deck_name
+                ));
+                egui::ScrollArea::vertical()
+                    .max_height(300.)
+                    .show(ui, |ui| {
+                        for note in existing {
+                            ui.separator();
+                            let mut fields: Vec<_> = note.fields.iter().collect();
+                            fields.sort_by_key(|(_, f)| f.order);
+                            egui::Grid::new(("duplicate", note.note_id))
+                                .num_columns(2)
+                                .show(ui, |ui| {
+                                    for (name, field) in fields {
+                                        ui.label(format!("{name}:"));
+                                        ui.label(&field.value);
+                                        ui.end_row();
+                                    }
+                                });
+                            if ui.button(format!("Update note {}", note.note_id)).clicked() {
+                                choice = Some(Choice::Update(note.note_id));
+                            }
+                        }
+                    });
+                ui.separator();
+                ui.label(format!(
+                    "New front: {}",
+                    fired.card.get(&self.r.settings.target.front_field)
+                ));
+                ui.horizontal(|ui| {
+                    if ui.button("Cancel").clicked() {
+                        choice = Some(Choice::Cancel);
+                    }
+                    if ui.button("Add Anyway").clicked() {
+                        choice = Some(Choice::AddAnyway);
+                    }
+                });
+            });
+
+        let Some(choice) = choice else {
+            return;
+        };
+        let (fired, _) = self.r.duplicates.take().unwrap();
+        let settings = self.r.settings.clone();
+        match choice {
+            Choice::Cancel => {}
+            Choice::AddAnyway => self.send(ctx.clone(), move |client| {
+                fire::add_card(client, &settings, fired, true).map(FireOutcome::Added)
+            }),
+            Choice::Update(id) => self.send(ctx.clone(), move |client| {
+                fire::update_card(client, &settings, id, fired).map(FireOutcome::Updated)
+            }),
+        }
+    }
+
+    fn batch_ui(&mut self, ctx: &egui::Context) {
+        if !self.r.batch.open {
+            return;
+        }
+        if let Some(path) = ctx.input(|i| i.raw.dropped_files.iter().find_map(|f| f.path.clone())) {
+            self.r.batch.path = path.display().to_string();
+            self.load_batch(ctx.clone());
+        }
+
+        let mut open = true;
+        let mut load = false;
+        let mut recheck = false;
+        let mut submit = false;
+        egui::Window::new("Batch Import")
+            .open(&mut open)
+            .default_size([700., 500.])
+            .show(ctx, |ui| {
+                let batch = &mut self.r.batch;
+                ui.horizontal(|ui| {
+                    let res = ui.add(
+                        egui::TextEdit::singleline(&mut batch.path)
+                            .hint_text("Path to a .csv or .tsv file, or drop one here")
+                            .desired_width(400.),
+                    );
+                    load = ui.button("Load").clicked()
+                        || res.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                });
+                if let Some(e) = &batch.error {
+                    ui.colored_label(ui.visuals().error_fg_color, e);
+                }
+                let Some(table) = &batch.table else {
                     return;
                 };
-                let fields = data.fields;
-                let sentence = ammonia::Builder::empty().clean(&fields.sentence_back.value);
 
-                let front = if front.is_empty() {
-                    format!("{}[{}]", fields.kanji.value, fields.kana.value)
-                } else {
-                    front
-                };
-                let back = if back.is_empty() {
-                    fields.meaning.value
-                } else {
-                    back
-                };
-                let back_paragraph = format!("{}\n{}", sentence, fields.picture.value)
-                    .trim()
-                    .replace('\n', "<br />");
-                let audio_guide = if audio_guide.is_empty() {
-                    fields.kanji.value
+                ui.collapsing("Columns", |ui| {
+                    egui::Grid::new("batch-columns")
+                        .num_columns(2)
+                        .show(ui, |ui| {
+                            for field in &self.r.target_fields {
+                                ui.label(format!("{field}:"));
+                                let mut col = batch.mapping.get(field).copied();
+                                let name = |col: Option<usize>| {
+                                    col.and_then(|c| table.headers.get(c))
+                                        .map_or("(none)", String::as_str)
+                                        .to_owned()
+                                };
+                                egui::ComboBox::from_id_source(("batch-column", field))
+                                    .selected_text(name(col))
+                                    .show_ui(ui, |ui| {
+                                        ui.selectable_value(&mut col, None, name(None));
+                                        for c in 0..table.headers.len() {
+                                            ui.selectable_value(&mut col, Some(c), name(Some(c)));
+                                        }
+                                    });
+                                if col != batch.mapping.get(field).copied() {
+                                    match col {
+                                        Some(c) => batch.mapping.insert(field.clone(), c),
+                                        None => batch.mapping.remove(field),
+                                    };
+                                    recheck = true;
+                                }
+                                ui.end_row();
+                            }
+                        });
+                });
+
+                let count = |f: fn(&BatchRow) -> bool| batch.rows.iter().filter(|r| f(r)).count();
+                ui.label(format!(
+                    "{} rows: {} ready, {} duplicates, {} invalid, {} added, {} failed",
+                    batch.rows.len(),
+                    count(|r| r.status == RowStatus::Ready),
+                    count(|r| r.status.is_duplicate()),
+                    count(|r| matches!(r.status, RowStatus::Invalid(_))),
+                    count(|r| matches!(r.status, RowStatus::Added(_))),
+                    count(|r| matches!(r.status, RowStatus::Failed(_))),
+                ));
+                let selected = count(BatchRow::can_submit);
+                ui.horizontal(|ui| {
+                    ui.add_enabled_ui(!batch.busy, |ui| {
+                        recheck |= ui.button("Check Again").clicked();
+                        submit = ui
+                            .add_enabled(
+                                selected > 0,
+                                egui::Button::new(format!("Add {selected} Notes")),
+                            )
+                            .clicked();
+                    });
+                    if batch.busy {
+                        ui.spinner();
+                    }
+                });
+
+                egui::ScrollArea::both().show(ui, |ui| {
+                    egui::Grid::new("batch-rows")
+                        .striped(true)
+                        .num_columns(self.r.target_fields.len() + 3)
+                        .show(ui, |ui| {
+                            ui.label("");
+                            ui.label("#");
+                            for field in &self.r.target_fields {
+                                ui.strong(field);
+                            }
+                            ui.strong("Status");
+                            ui.end_row();
+
+                            for (i, row) in batch.rows.iter_mut().enumerate() {
+                                let submittable = !matches!(
+                                    row.status,
+                                    RowStatus::Invalid(_) | RowStatus::Added(_)
+                                );
+                                ui.add_enabled(
+                                    submittable && !batch.busy,
+                                    egui::Checkbox::without_text(&mut row.include),
+                                );
+                                ui.label((i + 1).to_string());
+                                for field in &self.r.target_fields {
+                                    let value = row.card.get(field);
+                                    ui.label(preview::html_to_text(value)).on_hover_text(value);
+                                }
+                                let (text, error) = match &row.status {
+                                    RowStatus::Ready => ("Ready".to_owned(), false),
+                                    RowStatus::Invalid(e) => (e.clone(), true),
+                                    RowStatus::DuplicateInFile(first) => {
+                                        (format!("Same front as row {}", first + 1), true)
+                                    }
+                                    RowStatus::Duplicate => {
+                                        ("Already in collection".to_owned(), true)
+                                    }
+                                    RowStatus::Added(id) => (format!("Added note {id}"), false),
+                                    RowStatus::Failed(e) => (format!("Failed: {e}"), true),
+                                };
+                                if error {
+                                    ui.colored_label(ui.visuals().warn_fg_color, text);
+                                } else {
+                                    ui.label(text);
+                                }
+                                ui.end_row();
+                            }
+                        });
+                });
+            });
+
+        self.r.batch.open = open;
+        if load {
+            self.load_batch(ctx.clone());
+        } else if recheck {
+            self.check_batch(ctx.clone());
+        } else if submit {
+            self.submit_batch(ctx.clone());
+        }
+    }
+
+    fn target_ui(&mut self, ui: &mut egui::Ui) {
+        let target = &mut self.r.settings.target;
+        let mut model_changed = false;
+        let mut style_changed = false;
+        egui::Grid::new("target-grid")
+            .spacing([4.0, 4.0])
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Deck:");
+                combo_edit(ui, "target-deck", &mut target.deck_name, &self.r.decks);
+                ui.end_row();
+
+                ui.label("Note Type:");
+                model_changed =
+                    combo_edit(ui, "target-model", &mut target.model_name, &self.r.models);
+                ui.end_row();
+
+                ui.label("Front Field:");
+                combo_edit(
+                    ui,
+                    "target-front",
+                    &mut target.front_field,
+                    &self.r.target_fields,
+                );
+                ui.end_row();
+
+                ui.label("Back Field:");
+                combo_edit(
+                    ui,
+                    "target-back",
+                    &mut target.back_field,
+                    &self.r.target_fields,
+                );
+                ui.end_row();
+
+                ui.label("Audio Guide Field:");
+                combo_edit(
+                    ui,
+                    "target-audio-guide",
+                    &mut target.audio_guide_field,
+                    &self.r.target_fields,
+                );
+                ui.end_row();
+
+                ui.label("Audio Guide Style:");
+                let style = &mut target.audio_guide_style;
+                let before = *style;
+                egui::ComboBox::from_id_source("target-audio-guide-style")
+                    .selected_text(audio_guide_style_name(*style))
+                    .show_ui(ui, |ui| {
+                        for s in AUDIO_GUIDE_STYLES {
+                            ui.selectable_value(style, s, audio_guide_style_name(s));
+                        }
+                    });
+                style_changed = *style != before;
+                ui.end_row();
+
+                ui.label("Tags:");
+                ui.horizontal_wrapped(|ui| {
+                    target
+                        .tags
+                        .retain(|tag| !ui.small_button(format!("{tag} ✕")).clicked());
+                    let res = ui.add(
+                        egui::TextEdit::singleline(&mut self.r.new_tag)
+                            .hint_text("Add tag")
+                            .desired_width(100.),
+                    );
+                    if res.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                        for tag in self.r.new_tag.split_whitespace() {
+                            if !target.tags.iter().any(|t| t == tag) {
+                                target.tags.push(tag.to_owned());
+                            }
+                        }
+                        self.r.new_tag.clear();
+                        res.request_focus();
+                    }
+                });
+                ui.end_row();
+
+                ui.label("");
+                ui.checkbox(&mut target.from_tag, "Tag with from::<source deck>");
+                ui.end_row();
+
+                let add = &mut self.r.settings.add;
+                ui.label("");
+                ui.checkbox(
+                    &mut add.check_duplicates,
+                    "Look for existing notes with the same front first",
+                );
+                ui.end_row();
+
+                ui.label("");
+                ui.checkbox(
+                    &mut add.copy_media,
+                    "Copy referenced sounds and images to new files",
+                );
+                ui.end_row();
+
+                ui.label("Add Mode:");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut add.mode, AddMode::Dialog, "Add dialog");
+                    ui.radio_value(&mut add.mode, AddMode::Silent, "Silent add");
+                });
+                ui.end_row();
+
+                if add.mode == AddMode::Silent {
+                    ui.label("Duplicates:");
+                    ui.vertical(|ui| {
+                        ui.checkbox(&mut add.allow_duplicate, "Allow duplicates");
+                        ui.add_enabled_ui(!add.allow_duplicate, |ui| {
+                            ui.checkbox(&mut add.scope_deck, "Only check the target deck");
+                            ui.add_enabled(
+                                add.scope_deck,
+                                egui::Checkbox::new(&mut add.check_children, "Include child decks"),
+                            );
+                            ui.checkbox(&mut add.check_all_models, "Check all note types");
+                        });
+                    });
+                    ui.end_row();
+                }
+            });
+
+        if model_changed {
+            self.refresh_target_fields(ui.ctx().clone());
+        }
+        if style_changed && self.follow_front {
+            self.audio_guide_follow();
+        }
+        if ui.button("Refresh Decks and Note Types").clicked() {
+            self.refresh_collections(ui.ctx().clone());
+        }
+        self.save_settings_ui(ui);
+    }
+
+    fn connection_ui(&mut self, ui: &mut egui::Ui) {
+        let conn = &mut self.r.settings.connection;
+        egui::Grid::new("connection-grid")
+            .spacing([4.0, 4.0])
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("URL:");
+                ui.add(
+                    egui::TextEdit::singleline(&mut conn.url).hint_text("http://localhost:8765"),
+                );
+                ui.end_row();
+
+                ui.label("API Key:");
+                let mut key = conn.api_key.clone().unwrap_or_default();
+                if ui
+                    .add(egui::TextEdit::singleline(&mut key).password(true))
+                    .changed()
+                {
+                    conn.api_key = Some(key).filter(|k| !k.is_empty());
+                }
+                ui.end_row();
+
+                ui.label("Version:");
+                ui.add(egui::DragValue::new(&mut conn.version).range(1..=6));
+                ui.end_row();
+            });
+
+        if self.r.overrides != Overrides::default() {
+            ui.label("Some values are overridden by the command line or environment.");
+        }
+
+        self.save_settings_ui(ui);
+    }
+
+    fn pitch_ui(&mut self, ui: &mut egui::Ui) {
+        let pitch = &mut self.r.settings.pitch;
+        ui.checkbox(
+            &mut pitch.enabled,
+            "Annotate the front's pitch accent when the pitch field is blank",
+        );
+        egui::Grid::new("pitch-grid")
+            .spacing([4.0, 4.0])
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Accents File:");
+                ui.add(egui::TextEdit::singleline(&mut pitch.path).hint_text("accents.txt"))
+                    .on_hover_text("Kanjium's accents.txt, or lines of word, reading and downsteps separated by tabs");
+                ui.end_row();
+
+                ui.label("Pitch Field:");
+                combo_edit(ui, "pitch-field", &mut pitch.field, &self.r.target_fields);
+                ui.end_row();
+
+                ui.label("Format:");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut pitch.format, AccentFormat::Html, "Overline");
+                    ui.radio_value(&mut pitch.format, AccentFormat::Svg, "Graph");
+                });
+                ui.end_row();
+            });
+        self.save_settings_ui(ui);
+    }
+
+    /// Reopens the dictionary imported before, if any.
+    fn open_dictionary(&self, c: egui::Context) {
+        let Some(dir) = Dictionary::default_dir() else {
+            return;
+        };
+        self.fetch(c, move |_| {
+            Ok(match Dictionary::open(&dir) {
+                Ok(dict) => Fetched::Dictionary(Ok(dict)),
+                Err(_) => Fetched::Done,
+            })
+        });
+    }
+
+    fn import_dictionary(&mut self, c: egui::Context) {
+        let Some(dir) = Dictionary::default_dir() else {
+            self.r.lookup.error = Some("No settings directory to keep the dictionary in".into());
+            return;
+        };
+        let source = PathBuf::from(self.r.lookup.source.trim());
+        self.r.lookup.importing = true;
+        self.fetch(c, move |_| {
+            Ok(Fetched::Dictionary(
+                Dictionary::import(&source, &dir).map_err(|e| format!("Failed to import: {e}")),
+            ))
+        });
+    }
+
+    fn look_up(&mut self) {
+        let lookup = &mut self.r.lookup;
+        lookup.picked.clear();
+        let Some(dict) = &lookup.dictionary else {
+            return;
+        };
+        match dict.lookup(&lookup.word) {
+            Ok(entries) => {
+                lookup.entries = entries;
+                lookup.error = None;
+            }
+            Err(e) => {
+                lookup.entries.clear();
+                lookup.error = Some(format!("Failed to look up: {e}"));
+            }
+        }
+    }
+
+    /// The front without its readings, or the audio guide when the front is blank.
+    fn lookup_word(&self) -> String {
+        let target = &self.r.settings.target;
+        let front = self
+            .custom
+            .get(&target.front_field)
+            .map_or("", |s| s.as_str());
+        if front.trim().is_empty() {
+            self.custom
+                .get(&target.audio_guide_field)
+                .cloned()
+                .unwrap_or_default()
+        } else {
+            card::create_audio_guide(front)
+        }
+    }
+
+    /// Appends the picked senses to the back field, a line each.
+    fn insert_senses(&mut self) {
+        let lookup = &self.r.lookup;
+        let senses: Vec<String> = lookup
+            .picked
+            .iter()
+            .filter_map(|&(e, s)| lookup.entries.get(e)?.senses.get(s))
+            .map(|s| s.text())
+            .collect();
+        if senses.is_empty() {
+            return;
+        }
+        let back_field = self.r.settings.target.back_field.clone();
+        if !self.r.target_fields.contains(&back_field) {
+            return;
+        }
+        let back = self.custom.entry(back_field).or_default();
+        if !back.trim().is_empty() {
+            back.push_str("<br>");
+        }
+        back.push_str(&senses.join("<br>"));
+    }
+
+    fn dictionary_ui(&mut self, ui: &mut egui::Ui) {
+        let mut import = false;
+        let mut look_up = false;
+        let mut from_front = false;
+        let mut insert = false;
+        let back_field = &self.r.settings.target.back_field;
+        let has_back = self.r.target_fields.contains(back_field);
+        let lookup = &mut self.r.lookup;
+        match &lookup.dictionary {
+            Some(dict) => ui.label(format!("{} headwords", dict.len())),
+            None => ui.label("No dictionary imported"),
+        };
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut lookup.source)
+                    .hint_text("JMdict XML, EDICT (UTF-8 or EUC-JP) or a Yomichan .zip"),
+            );
+            import = ui
+                .add_enabled(!lookup.importing, egui::Button::new("Import"))
+                .clicked();
+            if lookup.importing {
+                ui.spinner();
+            }
+        });
+        if let Some(e) = &lookup.error {
+            ui.colored_label(ui.visuals().error_fg_color, e);
+        }
+        if lookup.dictionary.is_some() {
+            ui.horizontal(|ui| {
+                let res = ui.text_edit_singleline(&mut lookup.word);
+                look_up = ui.button("Look Up").clicked()
+                    || res.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                from_front = ui.button("Look Up Front").clicked();
+            });
+            for (e, entry) in lookup.entries.iter().enumerate() {
+                ui.separator();
+                let forms = entry.kanji.join("・");
+                let readings = entry.readings.join("・");
+                ui.strong(if forms.is_empty() {
+                    readings
                 } else {
-                    audio_guide
-                };
-                let audio = fields.kanken_audio.value;
-
-                GuiAddCardsFields {
-                    front,
-                    back,
-                    back_paragraph,
-                    audio_guide,
-                    audio,
+                    format!("{forms}【{readings}】")
+                });
+                for (s, sense) in entry.senses.iter().enumerate() {
+                    let mut picked = lookup.picked.contains(&(e, s));
+                    let pos = if sense.pos.is_empty() {
+                        String::new()
+                    } else {
+                        format!("({}) ", sense.pos.join(", "))
+                    };
+                    if ui
+                        .checkbox(&mut picked, format!("{}. {pos}{}", s + 1, sense.text()))
+                        .changed()
+                    {
+                        if picked {
+                            lookup.picked.insert((e, s));
+                        } else {
+                            lookup.picked.remove(&(e, s));
+                        }
+                    }
                 }
-            };
-
-            let Ok(_) = anki_request::<serde_json::Value>(
-                "guiAddCards".into(),
-                Some(serde_json::json!({
-                    "note": {
-                        "deckName": "Immersion",
-                        "modelName": "Immersion",
-                        "fields": new_card,
-                        "tags": [
-                            "Immersion",
-                            "from::KanKenDeck",
-                        ],
-                    },
-                })),
-            ) else {
-                return;
-            };
+            }
+            if !lookup.entries.is_empty() {
+                insert = ui
+                    .add_enabled(
+                        !lookup.picked.is_empty() && has_back,
+                        egui::Button::new(format!("Insert into {back_field}")),
+                    )
+                    .on_disabled_hover_text(if has_back {
+                        "Pick a sense first".to_owned()
+                    } else {
+                        format!("The note type has no {back_field} field")
+                    })
+                    .clicked();
+            } else if !lookup.word.is_empty() {
+                ui.label("No entries");
+            }
+        }
 
-            _ = sender.send(new_card);
-            c.request_repaint();
+        if import {
+            self.import_dictionary(ui.ctx().clone());
+        }
+        if from_front {
+            self.r.lookup.word = self.lookup_word();
+            look_up = true;
+        }
+        if look_up {
+            self.look_up();
+        }
+        if insert {
+            self.insert_senses();
+        }
+    }
+
+    fn tts_ui(&mut self, ui: &mut egui::Ui) {
+        let tts = &mut self.r.settings.tts;
+        ui.checkbox(
+            &mut tts.enabled,
+            "Voice the audio guide when the audio field is blank",
+        );
+        egui::Grid::new("tts-grid")
+            .spacing([4.0, 4.0])
+            .num_columns(2)
+            .show(ui, |ui| {
+                ui.label("Command:");
+                ui.add(egui::TextEdit::singleline(&mut tts.program).hint_text("espeak-ng"));
+                ui.end_row();
+
+                ui.label("Arguments:");
+                // one per line, keeping the blank ones while typing
+                let mut args = tts.args.join("\n");
+                if ui
+                    .add(
+                        egui::TextEdit::multiline(&mut args)
+                            .desired_rows(3)
+                            .hint_text("{output}\n{text}"),
+                    )
+                    .on_hover_text(
+                        "{text} is the audio guide and {output} the file to write. \
+                         Without {text}, the audio guide is piped to the command.",
+                    )
+                    .changed()
+                {
+                    tts.args = args.split('\n').map(String::from).collect();
+                }
+                ui.end_row();
+
+                ui.label("File Extension:");
+                ui.add(egui::TextEdit::singleline(&mut tts.extension).desired_width(60.));
+                ui.end_row();
+
+                ui.label("Audio Field:");
+                combo_edit(
+                    ui,
+                    "tts-audio-field",
+                    &mut tts.audio_field,
+                    &self.r.target_fields,
+                );
+                ui.end_row();
+            });
+        self.save_settings_ui(ui);
+    }
+
+    fn mapping_ui(&mut self, ui: &mut egui::Ui) {
+        let mut models: Vec<String> = self.r.settings.profiles.keys().cloned().collect();
+        if let Some(source) = &self.r.last_source {
+            if !models.contains(&source.model_name) {
+                models.push(source.model_name.clone());
+            }
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Source Note Type:");
+            egui::ComboBox::from_id_source("profile-model")
+                .selected_text(&self.r.profile_model)
+                .show_ui(ui, |ui| {
+                    for m in models {
+                        ui.selectable_value(&mut self.r.profile_model, m.clone(), m);
+                    }
+                });
+            ui.add(
+                egui::TextEdit::singleline(&mut self.r.profile_model)
+                    .hint_text("Note type name")
+                    .desired_width(150.),
+            );
         });
+
+        let model = self.r.profile_model.clone();
+        if model.is_empty() {
+            ui.label("Fire once or enter a note type name to edit its mapping.");
+            return;
+        }
+
+        let source_fields: Vec<String> = self
+            .r
+            .last_source
+            .as_ref()
+            .filter(|s| s.model_name == model)
+            .map(|s| s.field_names().into_iter().map(String::from).collect())
+            .unwrap_or_default();
+        if !source_fields.is_empty() {
+            ui.label(format!("Source fields: {}", source_fields.join(", ")));
+        }
+        ui.label(format!(
+            "Filters: {}, {}<field>",
+            template::FILTERS.join(", "),
+            template::READING_FILTER
+        ));
+
+        let profile = self.r.settings.profiles.entry(model.clone()).or_default();
+        let mut remove = false;
+        egui::Grid::new("mapping-grid")
+            .spacing([4.0, 4.0])
+            .num_columns(2)
+            .show(ui, |ui| {
+                for target in &self.r.target_fields {
+                    ui.label(format!("{target}:"));
+                    let src = profile.templates.entry(target.clone()).or_default();
+                    let valid = Template::parse(src);
+                    ui.vertical(|ui| {
+                        ui.add(egui::TextEdit::multiline(src).code_editor().desired_rows(1));
+                        if let Err(e) = valid {
+                            ui.colored_label(ui.visuals().error_fg_color, e.to_string());
+                        }
+                    });
+                    ui.end_row();
+                }
+            });
+
+        let mut cleaned_fields = source_fields;
+        for field in profile.policies.keys() {
+            if !cleaned_fields.contains(field) {
+                cleaned_fields.push(field.clone());
+            }
+        }
+        if !cleaned_fields.is_empty() {
+            ui.label("Cleaning of source fields:");
+            egui::Grid::new("policy-grid")
+                .spacing([4.0, 4.0])
+                .num_columns(2)
+                .show(ui, |ui| {
+                    for field in cleaned_fields {
+                        ui.label(format!("{field}:"));
+                        let mut policy = profile.policies.get(&field).copied().unwrap_or_default();
+                        egui::ComboBox::from_id_source(("policy", &field))
+                            .selected_text(policy.name())
+                            .show_ui(ui, |ui| {
+                                for p in Policy::ALL {
+                                    ui.selectable_value(&mut policy, p, p.name());
+                                }
+                            });
+                        if profile.policies.get(&field).copied().unwrap_or_default() != policy {
+                            profile.policies.insert(field, policy);
+                        }
+                        ui.end_row();
+                    }
+                });
+        }
+
+        ui.horizontal(|ui| {
+            if ui.button("Restore Defaults").clicked() {
+                *profile = MappingProfile::default();
+            }
+            if ui.button("Remove").clicked() {
+                remove = true;
+            }
+        });
+        if remove {
+            self.r.settings.profiles.remove(&model);
+        }
+        self.save_settings_ui(ui);
+    }
+
+    fn save_settings(&mut self) -> anyhow::Result<()> {
+        self.r.settings.save()?;
+        self.r.saved_settings = self.r.settings.clone();
+        self.r.settings_error = None;
+        Ok(())
+    }
+
+    fn save_settings_ui(&mut self, ui: &mut egui::Ui) {
+        if ui.button("Save").clicked() {
+            self.r.settings_status = Some(match self.save_settings() {
+                Ok(()) => "Saved".into(),
+                Err(e) => format!("Failed to save: {e}"),
+            });
+        }
+        if let Some(status) = &self.r.settings_status {
+            ui.label(status);
+        }
     }
 }
 
-fn create_audio_guide(s: &str) -> String {
-    let s = s.replace(&['(', ')', '{', '}', ' '], "");
-    let r = Regex::new(r"\[[^\]]*\]").unwrap();
-    r.replace_all(&s, "").to_string()
+/// Notes fired through the Add dialog are never added if the dialog was cancelled.
+fn ensure_note_exists(client: &Client, id: i64) -> Result<(), AnkiError> {
+    if client.find_notes(&format!("nid:{id}"))?.is_empty() {
+        return Err(AnkiError::Anki(format!(
+            "note {id} does not exist, so the Add dialog may have been cancelled"
+        )));
+    }
+    Ok(())
 }
-#[cfg(test)]
-mod tests {
-    use super::create_audio_guide;
-    #[test]
-    fn test_create_audio_guide() {
-        assert_eq!(
-            create_audio_guide("ab[]c[de]f gh[ij]k (lmn) {op}"),
-            "abcfghklmnop".to_string()
-        );
+
+const AUDIO_GUIDE_STYLES: [AudioGuideStyle; 5] = [
+    AudioGuideStyle::Kanji,
+    AudioGuideStyle::Hiragana,
+    AudioGuideStyle::Katakana,
+    AudioGuideStyle::Romaji(RomajiSystem::Hepburn),
+    AudioGuideStyle::Romaji(RomajiSystem::Kunrei),
+];
+
+fn audio_guide_style_name(style: AudioGuideStyle) -> &'static str {
+    match style {
+        AudioGuideStyle::Kanji => "Kanji (噛み殺す)",
+        AudioGuideStyle::Hiragana => "Hiragana (かみころす)",
+        AudioGuideStyle::Katakana => "Katakana (カミコロス)",
+        AudioGuideStyle::Romaji(RomajiSystem::Hepburn) => "Hepburn romaji (shashin)",
+        AudioGuideStyle::Romaji(RomajiSystem::Kunrei) => "Kunrei romaji (syasin)",
     }
 }
 
+/// A text edit with a drop-down of known values next to it.
+/// Returns true once a new value is picked or typed in.
+fn combo_edit(ui: &mut egui::Ui, id: &str, value: &mut String, options: &[String]) -> bool {
+    ui.horizontal(|ui| {
+        let edited = ui
+            .add(egui::TextEdit::singleline(value).desired_width(150.))
+            .lost_focus();
+        let mut picked = false;
+        egui::ComboBox::from_id_source(id)
+            .selected_text("")
+            .width(20.)
+            .show_ui(ui, |ui| {
+                for o in options {
+                    picked |= ui.selectable_value(value, o.clone(), o).changed();
+                }
+            });
+        edited || picked
+    })
+    .inner
+}
+
 impl eframe::App for AppState {
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        eframe::set_value(storage, SESSION_KEY, &self.session());
+        if self.r.settings_error.is_some() || self.r.settings == self.r.saved_settings {
+            return;
+        }
+        if let Err(e) = self.save_settings() {
+            self.r.settings_status = Some(format!("Failed to save: {e}"));
+        }
+    }
+
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        self.auto_refresh_preview(ctx);
+        self.auto_poll_current_card(ctx);
+
+        egui::SidePanel::right("history-panel")
+            .resizable(true)
+            .default_width(250.)
+            .show(ctx, |ui| self.history_ui(ui));
+
         egui::CentralPanel::default()
             .frame(egui::Frame {
                 inner_margin: egui::Margin::same(10.),
                 ..Default::default()
             })
             .show(ctx, |ui| {
-                if let Ok(new_card) = self.r.req_complete.try_recv() {
-                    self.reset();
-                    self.r.prev_card = if self.r.maintain_prev {
-                        Some(new_card)
-                    } else {
-                        None
-                    };
+                while let Ok(res) = self.r.req_complete.try_recv() {
+                    self.r.in_flight -= 1;
+                    match res {
+                        Ok(FireOutcome::Duplicates(fired, existing)) => {
+                            self.r.duplicates = Some((fired, existing));
+                        }
+                        Ok(FireOutcome::Added(fired)) => self.on_fired(fired, false),
+                        Ok(FireOutcome::Updated(fired)) => self.on_fired(fired, true),
+                        Err(e) => self.r.status = Some(Err(e)),
+                    }
+                }
+                while let Ok(res) = self.r.fetched.try_recv() {
+                    match res {
+                        Ok(Fetched::Decks(decks)) => self.r.decks = decks,
+                        Ok(Fetched::Models(models)) => self.r.models = models,
+                        Ok(Fetched::NoteDeleted(id)) => {
+                            for entry in &mut self.r.history {
+                                if entry.fired.note_id == Some(id) {
+                                    entry.deleted = true;
+                                }
+                            }
+                            self.r.status = Some(Ok(format!("Deleted note {id}")));
+                        }
+                        Ok(Fetched::Preview(res)) => {
+                            self.r.preview_pending = false;
+                            if let Ok(Fired {
+                                source: Some(source),
+                                ..
+                            }) = &res
+                            {
+                                self.r.last_source = Some(source.clone());
+                            }
+                            self.r.preview = Some(res);
+                        }
+                        Ok(Fetched::Watched(res)) => {
+                            self.r.watch_pending = false;
+                            match res {
+                                Ok(Some(card)) if self.r.watch => self.on_watched(card),
+                                Ok(_) if !self.r.watch => {}
+                                Ok(_) => self.r.watched = Some(Err(AnkiError::NoCurrentCard)),
+                                Err(e) => self.r.watched = Some(Err(e)),
+                            }
+                        }
+                        Ok(Fetched::BatchChecked(rows, err)) => {
+                            self.r.batch.rows = rows;
+                            self.r.batch.busy = false;
+                            self.r.batch.error =
+                                err.map(|e| format!("Failed to check duplicates: {e}"));
+                        }
+                        Ok(Fetched::BatchSubmitted(rows)) => {
+                            self.r.batch.busy = false;
+                            let before = self.r.batch.rows.iter();
+                            let added = rows
+                                .iter()
+                                .zip(before)
+                                .filter(|(r, b)| {
+                                    matches!(r.status, RowStatus::Added(_)) && r.status != b.status
+                                })
+                                .count();
+                            self.r.fired += added as i64;
+                            self.r.status = Some(Ok(format!("Batch added {added} note(s)")));
+                            self.r.batch.rows = rows;
+                        }
+                        Ok(Fetched::Dictionary(res)) => {
+                            self.r.lookup.importing = false;
+                            match res {
+                                Ok(dict) => {
+                                    self.r.lookup.dictionary = Some(dict);
+                                    self.r.lookup.error = None;
+                                }
+                                Err(e) => self.r.lookup.error = Some(e),
+                            }
+                        }
+                        Ok(Fetched::Done) => {}
+                        Ok(Fetched::ModelFields(model, fields)) => {
+                            if model == self.r.settings.target.model_name {
+                                self.r.target_fields = fields;
+                            }
+                        }
+                        Err(e) => self.r.status = Some(Err(e)),
+                    }
                 }
 
                 ui.heading("Anki Copy Card");
 
+                if let Some(e) = &self.r.settings_error {
+                    ui.colored_label(
+                        ui.visuals().error_fg_color,
+                        format!(
+                            "{e}\nUsing the defaults, which are not saved until you press Save."
+                        ),
+                    );
+                }
+
+                if let Some(status) = &self.r.status {
+                    let (text, color) = match status {
+                        Ok(msg) => (msg.clone(), ui.visuals().text_color()),
+                        Err(e) => (e.to_string(), ui.visuals().error_fg_color),
+                    };
+                    let mut dismiss = false;
+                    ui.horizontal(|ui| {
+                        ui.colored_label(color, text);
+                        dismiss = ui.small_button("✕").clicked();
+                    });
+                    if dismiss {
+                        self.r.status = None;
+                    }
+                }
+
                 egui::Grid::new("main-grid")
                     .spacing([4.0, 4.0])
                     .num_columns(2)
                     .striped(true)
                     .show(ui, |ui| {
-                        ui.label("Custom Front:");
-                        if ui
-                            .add(
-                                egui::TextEdit::singleline(&mut self.front)
-                                    .hint_text("噛[か]み 殺[ころ]す"),
-                            )
-                            .changed()
-                            && self.follow_front
-                        {
-                            self.audio_guide_follow();
+                        for field in self.r.target_fields.clone() {
+                            self.field_row(ui, &field);
+                            ui.end_row();
                         }
-                        ui.end_row();
-
-                        ui.label("Custom Audio Guide:");
-                        ui.vertical(|ui| {
-                            ui.add(
-                                egui::TextEdit::singleline(&mut self.audio_guide)
-                                    .hint_text("噛み殺す"),
-                            );
-                            if ui
-                                .checkbox(&mut self.follow_front, "Follow Front")
-                                .changed()
-                                && self.follow_front
-                            {
-                                self.audio_guide_follow();
-                            };
-                        });
-                        ui.end_row();
-
-                        ui.label("Back:");
-                        ui.add(
-                            egui::TextEdit::multiline(&mut self.back)
-                                .hint_text("to stifle a smile, yawn, etc.\nto bite to death"),
-                        );
-                        ui.end_row();
                     });
 
                 ui.vertical(|ui| {
@@ -341,32 +1751,74 @@ impl eframe::App for AppState {
                     );
                     ui.horizontal(|ui| {
                         if ui.button("Fire").clicked() {
-                            let fired = self.r.fired;
                             self.fire(ctx.clone());
-                            self.r.fired = fired + 1;
                         }
                         if ui.button("Reset").clicked() {
                             self.reset();
                         }
+                        if ui.button("Batch Import…").clicked() {
+                            self.r.batch.open = true;
+                        }
                     });
 
+                    let front_field = &self.r.settings.target.front_field;
                     if let Some(p) = &self.r.prev_card {
                         ui.label(format!(
                             "Firing will be based on previous card fired: {}",
-                            p.front
+                            p.card.get(front_field)
                         ));
                         if ui.button("Reset Previous Card").clicked() {
                             self.r.prev_card = None;
                         }
+                    } else if let (true, Some(Ok(card))) = (self.r.watch, &self.r.watched) {
+                        let first = card.field_names().first().map(|n| &card.fields[*n]);
+                        let kanji = card.fields.get("Kanji").or(first);
+                        ui.strong(format!(
+                            "Firing will be based on the card being reviewed: {} ({})",
+                            kanji.map_or("", |f| f.value.as_str()),
+                            card.deck_name
+                        ));
+                    } else {
+                        ui.label("Firing will be based on the card being reviewed.");
                     }
 
                     ui.label(format!("Fired: {}", self.r.fired));
+                    if self.r.in_flight > 0 {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label(format!("Waiting for {} request(s)", self.r.in_flight));
+                        });
+                    }
                 });
+
+                egui::CollapsingHeader::new("Reviewer")
+                    .default_open(true)
+                    .show(ui, |ui| self.watch_ui(ui));
+                ui.collapsing("Preview", |ui| self.preview_ui(ui));
+                ui.collapsing("Target", |ui| self.target_ui(ui));
+                ui.collapsing("Connection", |ui| self.connection_ui(ui));
+                ui.collapsing("Text to Speech", |ui| self.tts_ui(ui));
+                ui.collapsing("Pitch Accent", |ui| self.pitch_ui(ui));
+                ui.collapsing("Dictionary", |ui| self.dictionary_ui(ui));
+                ui.collapsing("Field Mapping", |ui| self.mapping_ui(ui));
             });
+
+        self.duplicates_ui(ctx);
+        self.batch_ui(ctx);
     }
 }
 
 fn main() -> eframe::Result {
+    let mut overrides = Overrides::from_env();
+    match overrides.parse_args(std::env::args().skip(1)) {
+        Ok(rest) if rest.is_empty() => {}
+        Ok(rest) => std::process::exit(cli::run(rest, &overrides)),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    }
+
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default().with_inner_size([800., 600.]),
         ..Default::default()
@@ -375,6 +1827,59 @@ fn main() -> eframe::Result {
     eframe::run_native(
         "anki-copy-card-egui",
         native_options,
-        Box::new(|cc| Ok(Box::new(AppState::new(cc)))),
+        Box::new(|cc| Ok(Box::new(AppState::new(cc, overrides)))),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AppState, Session};
+    use anki_copy_card_egui::{
+        card::{MappingProfile, SourceCard},
+        config::AudioGuideStyle,
+    };
+
+    #[test]
+    fn test_session_defaults() {
+        let session: Session = serde_json::from_str(r#"{"fired": 3}"#).unwrap();
+        assert_eq!(session.fired, 3);
+        assert!(session.follow_front);
+        assert!(session.prev_card.is_none());
+        assert!(!session.watch);
+        assert_eq!(session.watch_interval, 1.);
+    }
+
+    #[test]
+    fn test_on_watched_follows_front() {
+        let card: SourceCard = serde_json::from_value(serde_json::json!({
+            "deckName": "KanKenDeck",
+            "modelName": "KanKen",
+            "fields": {
+                "Kanji": { "value": "噛み殺す", "order": 0 },
+                "Kana": { "value": "かみころす", "order": 1 },
+                "Meaning": { "value": "to stifle a yawn", "order": 2 },
+            },
+        }))
+        .unwrap();
+        let mut app = AppState::default();
+        app.r.settings.target.audio_guide_style = AudioGuideStyle::Hiragana;
+        app.r.settings.target.back_field = "Meaning".into();
+        let mut profile = MappingProfile::default();
+        profile
+            .templates
+            .insert("Meaning".into(), "{{Meaning}}".into());
+        app.r.settings.profiles.insert("KanKen".into(), profile);
+        app.r.target_fields = ["Front", "Meaning", "AudioGuide"]
+            .map(String::from)
+            .to_vec();
+        app.on_watched(card.clone());
+        assert_eq!(app.custom["Front"], "噛[か]み 殺[ころ]す");
+        assert_eq!(app.custom["Meaning"], "to stifle a yawn");
+        assert_eq!(app.custom["AudioGuide"], "かみころす");
+
+        app.follow_front = false;
+        app.r.last_watched = None;
+        app.on_watched(card);
+        assert_eq!(app.custom["AudioGuide"], "噛み殺す");
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+
+use regex::{Captures, Regex};
+
+use crate::{
+    ankiconnect::{Client, MediaSource},
+    card::GuiAddCardsFields,
+    error::AnkiError,
+};
+
+/// Prefixed to the filenames of copied media, which are then left alone.
+pub const MEDIA_PREFIX: &str = "anki-copy-card-";
+
+fn sound_regex() -> Regex {
+    Regex::new(r"\[sound:([^\]]+)\]").unwrap()
+}
+
+fn img_regex() -> Regex {
+    Regex::new(r#"(?i)(<img\b[^>]*?\bsrc\s*=\s*)(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#).unwrap()
+}
+
+/// Only files in the collection's media folder, not remote or inline ones.
+fn is_local(name: &str) -> bool {
+    !name.is_empty() && !name.contains("://") && !name.starts_with("data:")
+}
+
+/// The `[sound:...]` and `<img src>` filenames referenced by a field, in order.
+pub fn media_refs(s: &str) -> Vec<String> {
+    let sounds = sound_regex()
+        .captures_iter(s)
+        .map(|c| c[1].to_owned())
+        .collect::<Vec<_>>();
+    let imgs = img_regex()
+        .captures_iter(s)
+        .filter_map(|c| c.get(2).or(c.get(3)).or(c.get(4)))
+        .map(|m| m.as_str().to_owned())
+        .collect::<Vec<_>>();
+    let mut refs = vec![];
+    for name in sounds.into_iter().chain(imgs) {
+        if is_local(&name) && !refs.contains(&name) {
+            refs.push(name);
+        }
+    }
+    refs
+}
+
+/// Replaces the media filenames found in `renamed`, keeping the quoting of `src`.
+pub fn rewrite_refs(s: &str, renamed: &BTreeMap<String, String>) -> String {
+    let new_name = |old: &str| renamed.get(old).cloned().unwrap_or_else(|| old.to_owned());
+    let s = sound_regex().replace_all(s, |c: &Captures| format!("[sound:{}]", new_name(&c[1])));
+    img_regex()
+        .replace_all(&s, |c: &Captures| {
+            let (quote, m) = match (c.get(2), c.get(3), c.get(4)) {
+                (Some(m), _, _) => ("\"", m),
+                (_, Some(m), _) => ("'", m),
+                (_, _, Some(m)) => ("", m),
+                _ => unreachable!(),
+            };
+            format!("{}{quote}{}{quote}", &c[1], new_name(m.as_str()))
+        })
+        .into_owned()
+}
+
+/// Copies the media referenced by `card` under [`MEDIA_PREFIX`] and points the
+/// fields at the copies, so the card outlives the source note's files.
+/// Files missing from the collection are left referenced as they are.
+pub fn copy_media(client: &Client, card: &mut GuiAddCardsFields) -> Result<(), AnkiError> {
+    let mut renamed = BTreeMap::new();
+    for value in card.0.values() {
+        for name in media_refs(value) {
+            if name.starts_with(MEDIA_PREFIX) || renamed.contains_key(&name) {
+                continue;
+            }
+            let Some(data) = client.retrieve_media_file(&name)? else {
+                continue;
+            };
+            let stored = client
+                .store_media_file(&format!("{MEDIA_PREFIX}{name}"), &MediaSource::Data(data))?;
+            renamed.insert(name, stored);
+        }
+    }
+    if renamed.is_empty() {
+        return Ok(());
+    }
+    for value in card.0.values_mut() {
+        *value = rewrite_refs(value, &renamed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_media_refs() {
+        assert_eq!(
+            media_refs(
+                r#"[sound:a.mp3]<br /><IMG class="x" src="b c.jpg"><img src='d.png'/><img src=e.gif>[sound:a.mp3]"#
+            ),
+            vec!["a.mp3", "b c.jpg", "d.png", "e.gif"]
+        );
+        assert_eq!(
+            media_refs(
+                r#"<img src="https://example.com/a.jpg"><img src="data:image/png;base64,AA">"#
+            ),
+            Vec::<String>::new()
+        );
+        assert_eq!(media_refs("欠伸を噛み殺す"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_rewrite_refs() {
+        let renamed = BTreeMap::from([
+            ("a.mp3".to_owned(), "x-a.mp3".to_owned()),
+            ("b.jpg".to_owned(), "x-b.jpg".to_owned()),
+        ]);
+        assert_eq!(
+            rewrite_refs(
+                r#"[sound:a.mp3][sound:c.mp3]<img alt="" src="b.jpg"><img src='b.jpg'><img src=b.jpg>"#,
+                &renamed
+            ),
+            r#"[sound:x-a.mp3][sound:c.mp3]<img alt="" src="x-b.jpg"><img src='x-b.jpg'><img src=x-b.jpg>"#
+        );
+    }
+}
@@ -0,0 +1,311 @@
+//! Pitch accents from a local dataset in Kanjium's `accents.txt` format.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::{
+    card::{create_audio_guide, GuiAddCardsFields},
+    config::{AccentFormat, PitchAccent},
+    error::AnkiError,
+    furigana, kana,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accent {
+    /// In hiragana.
+    pub reading: String,
+    /// The morae before each drop in pitch, `0` for none.
+    pub downsteps: Vec<usize>,
+}
+
+#[derive(Debug, Default)]
+pub struct Accents(BTreeMap<String, Vec<Accent>>);
+
+impl Accents {
+    /// Reads lines of `word`, `reading` and comma-separated downsteps, tab-separated.
+    /// The reading is blank for words written in kana, and downsteps may be
+    /// prefixed with a part of speech, as in `(名)0,(副)1`.
+    pub fn parse(text: &str) -> Self {
+        let mut words: BTreeMap<String, Vec<Accent>> = BTreeMap::new();
+        for line in text.lines() {
+            let mut cols = line.split('\t');
+            let (Some(word), Some(reading), Some(accents)) =
+                (cols.next(), cols.next(), cols.next())
+            else {
+                continue;
+            };
+            let mut downsteps = vec![];
+            for a in accents.split(',') {
+                let a = a.rsplit(')').next().unwrap_or(a).trim();
+                if let Ok(n) = a.parse::<usize>() {
+                    if !downsteps.contains(&n) {
+                        downsteps.push(n);
+                    }
+                }
+            }
+            if word.is_empty() || downsteps.is_empty() {
+                continue;
+            }
+            let reading = if reading.is_empty() { word } else { reading };
+            words.entry(word.to_owned()).or_default().push(Accent {
+                reading: kana::to_hiragana(reading),
+                downsteps,
+            });
+        }
+        Self(words)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, AnkiError> {
+        fs::read_to_string(path)
+            .map(|text| Self::parse(&text))
+            .map_err(|e| AnkiError::Pitch(format!("{}: {e}", path.display())))
+    }
+
+    /// Like [`Accents::load`], reusing the last file read until it changes.
+    pub fn load_cached(path: &Path) -> Result<Arc<Self>, AnkiError> {
+        type Cached = (PathBuf, Option<SystemTime>, Arc<Accents>);
+        static CACHE: Mutex<Option<Cached>> = Mutex::new(None);
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((p, m, accents)) = &*cache {
+            if p == path && *m == modified && modified.is_some() {
+                return Ok(accents.clone());
+            }
+        }
+        let accents = Arc::new(Self::load(path)?);
+        *cache = Some((path.to_owned(), modified, accents.clone()));
+        Ok(accents)
+    }
+
+    /// The accents of `word`, only those read as `reading` when any are.
+    pub fn lookup(&self, word: &str, reading: Option<&str>) -> Vec<&Accent> {
+        let all = self.0.get(word).map_or(&[][..], Vec::as_slice);
+        let reading = reading.map(kana::to_hiragana);
+        let matching: Vec<&Accent> = all
+            .iter()
+            .filter(|a| reading.as_ref() == Some(&a.reading))
+            .collect();
+        if matching.is_empty() {
+            all.iter().collect()
+        } else {
+            matching
+        }
+    }
+}
+
+/// Splits kana into morae, keeping small kana with the one before them.
+pub fn morae(reading: &str) -> Vec<String> {
+    let mut morae: Vec<String> = vec![];
+    for c in reading.chars() {
+        let small = "ゃゅょぁぃぅぇぉゎャュョァィゥェォヮ".contains(c);
+        match morae.last_mut() {
+            Some(last) if small => last.push(c),
+            _ => morae.push(c.to_string()),
+        }
+    }
+    morae
+}
+
+/// Whether the `i`th mora is high, where the mora after the word is a particle.
+fn is_high(downstep: usize, i: usize) -> bool {
+    match downstep {
+        0 => i > 0,
+        1 => i == 0,
+        n => i > 0 && i < n,
+    }
+}
+
+/// The reading overlined where it is high, with a tick where the pitch drops.
+pub fn html(reading: &str, downstep: usize) -> String {
+    let morae = morae(reading);
+    let mut out = String::from(r#"<span class="pitch">"#);
+    let mut i = 0;
+    while i < morae.len() {
+        let high = is_high(downstep, i);
+        let start = i;
+        while i < morae.len() && is_high(downstep, i) == high {
+            i += 1;
+        }
+        let text = morae[start..i].concat();
+        if !high {
+            out.push_str(&text);
+        } else if i == downstep {
+            out.push_str(&format!(
+                r#"<span style="border-top:1px solid;border-right:1px solid">{text}</span>"#
+            ));
+        } else {
+            out.push_str(&format!(
+                r#"<span style="border-top:1px solid">{text}</span>"#
+            ));
+        }
+    }
+    out.push_str("</span>");
+    out
+}
+
+/// A graph of the pitch over each mora, ending with a hollow dot for the particle.
+pub fn svg(reading: &str, downstep: usize) -> String {
+    const STEP: usize = 30;
+    let morae = morae(reading);
+    let y = |i| if is_high(downstep, i) { 10 } else { 30 };
+    let x = |i: usize| STEP / 2 + i * STEP;
+    let width = (morae.len() + 1) * STEP;
+    let mut out = format!(
+        r#"<svg class="pitch" xmlns="http://www.w3.org/2000/svg" width="{width}" height="60" viewBox="0 0 {width} 60">"#
+    );
+    let points: Vec<String> = (0..=morae.len())
+        .map(|i| format!("{},{}", x(i), y(i)))
+        .collect();
+    out.push_str(&format!(
+        r#"<polyline points="{}" fill="none" stroke="currentColor" stroke-width="1.5"/>"#,
+        points.join(" ")
+    ));
+    for (i, mora) in morae.iter().enumerate() {
+        out.push_str(&format!(
+            r#"<circle cx="{}" cy="{}" r="4" fill="currentColor"/><text x="{}" y="55" font-size="16" text-anchor="middle" fill="currentColor">{mora}</text>"#,
+            x(i),
+            y(i),
+            x(i)
+        ));
+    }
+    out.push_str(&format!(
+        r#"<circle cx="{}" cy="{}" r="4" fill="none" stroke="currentColor" stroke-width="1.5"/></svg>"#,
+        x(morae.len()),
+        y(morae.len())
+    ));
+    out
+}
+
+pub fn render(accents: &[&Accent], format: AccentFormat) -> String {
+    let render = match format {
+        AccentFormat::Html => html,
+        AccentFormat::Svg => svg,
+    };
+    accents
+        .iter()
+        .flat_map(|a| a.downsteps.iter().map(|&d| render(&a.reading, d)))
+        .collect::<Vec<_>>()
+        .join("・")
+}
+
+/// Annotates the front's word in the pitch field when that is blank.
+pub fn fill_accent(
+    pitch: &PitchAccent,
+    front_field: &str,
+    card: &mut GuiAddCardsFields,
+) -> Result<(), AnkiError> {
+    if !card.0.contains_key(&pitch.field) || !card.get(&pitch.field).trim().is_empty() {
+        return Ok(());
+    }
+    let front = card.get(front_field);
+    let word = create_audio_guide(front);
+    if word.is_empty() {
+        return Ok(());
+    }
+    let reading = furigana::reading_guide(&furigana::parse(front));
+    let accents = Accents::load_cached(Path::new(&pitch.path))?;
+    let found = accents.lookup(&word, Some(&reading));
+    if !found.is_empty() {
+        card.set(&pitch.field, render(&found, pitch.format));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACCENTS: &str = "噛み殺す\tかみころす\t4\n\
+        箸\tはし\t1\n\
+        橋\tはし\t2\n\
+        端\tはし\t0\n\
+        ああ\t\t(感)1,(副)0\n\
+        今日\tきょう\t1\n\
+        今日\tこんにち\t0\n";
+
+    #[test]
+    fn test_parse_and_lookup() {
+        let accents = Accents::parse(ACCENTS);
+        assert_eq!(
+            accents.lookup("ああ", None),
+            vec![&Accent {
+                reading: "ああ".into(),
+                downsteps: vec![1, 0],
+            }]
+        );
+        let today = accents.lookup("今日", Some("コンニチ"));
+        assert_eq!(today.len(), 1);
+        assert_eq!(today[0].downsteps, vec![0]);
+        assert_eq!(accents.lookup("今日", Some("いま")).len(), 2);
+        assert!(accents.lookup("欠伸", None).is_empty());
+    }
+
+    #[test]
+    fn test_morae() {
+        assert_eq!(morae("きょうと"), vec!["きょ", "う", "と"]);
+        assert_eq!(morae("シャッター"), vec!["シャ", "ッ", "タ", "ー"]);
+    }
+
+    #[test]
+    fn test_html() {
+        let high = |t| format!(r#"<span style="border-top:1px solid">{t}</span>"#);
+        let drop =
+            |t| format!(r#"<span style="border-top:1px solid;border-right:1px solid">{t}</span>"#);
+        let pitch = |s: String| format!(r#"<span class="pitch">{s}</span>"#);
+        assert_eq!(html("はし", 0), pitch(format!("は{}", high("し"))));
+        assert_eq!(html("はし", 1), pitch(format!("{}し", drop("は"))));
+        assert_eq!(html("はし", 2), pitch(format!("は{}", drop("し"))));
+        assert_eq!(
+            html("かみころす", 4),
+            pitch(format!("か{}す", drop("みころ")))
+        );
+        assert_eq!(html("きょう", 1), pitch(format!("{}う", drop("きょ"))));
+    }
+
+    #[test]
+    fn test_svg() {
+        let svg = svg("はし", 2);
+        assert!(svg.starts_with(r#"<svg class="pitch""#), "{svg}");
+        assert!(svg.contains(r#"points="15,30 45,10 75,30""#), "{svg}");
+        assert!(
+            svg.contains(">は</text>") && svg.contains(">し</text>"),
+            "{svg}"
+        );
+    }
+
+    #[test]
+    fn test_fill_accent() {
+        let path =
+            std::env::temp_dir().join(format!("anki-copy-card-accents-{}.txt", std::process::id()));
+        fs::write(&path, ACCENTS).unwrap();
+        let pitch = PitchAccent {
+            enabled: true,
+            path: path.display().to_string(),
+            ..Default::default()
+        };
+        let mut card = GuiAddCardsFields::default();
+        card.set("Front", "今日[こんにち]は".into());
+        card.set("Pitch", String::new());
+        fill_accent(&pitch, "Front", &mut card).unwrap();
+        assert_eq!(card.get("Pitch"), "");
+
+        card.set("Front", "今日[こんにち]".into());
+        fill_accent(&pitch, "Front", &mut card).unwrap();
+        assert_eq!(card.get("Pitch"), html("こんにち", 0));
+
+        card.set("Front", "箸[はし]".into());
+        fill_accent(&pitch, "Front", &mut card).unwrap();
+        assert_eq!(card.get("Pitch"), html("こんにち", 0), "kept as it was");
+
+        let mut card = GuiAddCardsFields::default();
+        card.set("Front", "箸[はし]".into());
+        fill_accent(&pitch, "Front", &mut card).unwrap();
+        assert!(!card.0.contains_key("Pitch"), "not a target field");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+use regex::Regex;
+
+use crate::furigana::{self, Token};
+
+/// Splits Anki's `base[reading]` furigana into segments, where plain text has no reading.
+pub fn ruby_segments(s: &str) -> Vec<(String, Option<String>)> {
+    furigana::parse(s)
+        .into_iter()
+        .map(|t| match t {
+            Token::Text(t) | Token::Tag(t) => (t, None),
+            Token::Ruby { base, reading } => (base, Some(reading)),
+        })
+        .collect()
+}
+
+/// Approximates how a field displays in Anki: line breaks are kept, other tags dropped.
+pub fn html_to_text(s: &str) -> String {
+    let br = Regex::new(r"(?i)<br\s*/?>").unwrap();
+    let tag = Regex::new(r"<[^>]*>").unwrap();
+    let s = br.replace_all(s, "\n");
+    let s = tag.replace_all(&s, "");
+    s.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Shows furigana as small text above its base characters.
+pub fn ruby_label(ui: &mut egui::Ui, s: &str) {
+    ui.with_layout(
+        egui::Layout::left_to_right(egui::Align::Max).with_main_wrap(true),
+        |ui| {
+            ui.spacing_mut().item_spacing = egui::vec2(0., 0.);
+            for (base, reading) in ruby_segments(&html_to_text(s)) {
+                match reading {
+                    Some(reading) => {
+                        ui.vertical(|ui| {
+                            ui.label(egui::RichText::new(reading).small());
+                            ui.label(base);
+                        });
+                    }
+                    None => {
+                        ui.label(base);
+                    }
+                }
+            }
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ruby_segments() {
+        let seg = |b: &str, r: Option<&str>| (b.to_owned(), r.map(String::from));
+        assert_eq!(
+            ruby_segments("噛[か]み 殺[ころ]す"),
+            vec![
+                seg("噛", Some("か")),
+                seg("み", None),
+                seg("殺", Some("ころ")),
+                seg("す", None),
+            ]
+        );
+        assert_eq!(
+            ruby_segments("[sound:a.mp3]"),
+            vec![seg("[sound:a.mp3]", None)]
+        );
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        assert_eq!(
+            html_to_text("欠伸を<b>噛み殺す</b><br />a &amp; b"),
+            "欠伸を噛み殺す\na & b"
+        );
+    }
+}
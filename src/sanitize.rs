@@ -0,0 +1,152 @@
+//! How much of a source field's HTML is copied, chosen per field.
+
+use std::collections::HashSet;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Each policy keeps what the one before it does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Policy {
+    /// Only the text, without `[sound:...]` either.
+    StripAll,
+    /// Emphasis like `<b>` and `<i>`, line breaks and coloured spans.
+    KeepFormatting,
+    /// `<ruby>` furigana too.
+    KeepRuby,
+    /// `<img>` and `[sound:...]` too.
+    KeepMedia,
+    /// The field as it is.
+    #[default]
+    Passthrough,
+}
+
+const FORMATTING: &[&str] = &[
+    "b", "i", "u", "s", "em", "strong", "mark", "small", "sub", "sup", "br", "p", "div", "span",
+    "font",
+];
+const RUBY: &[&str] = &["ruby", "rb", "rt", "rp"];
+
+impl Policy {
+    pub const ALL: [Policy; 5] = [
+        Policy::StripAll,
+        Policy::KeepFormatting,
+        Policy::KeepRuby,
+        Policy::KeepMedia,
+        Policy::Passthrough,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Policy::StripAll => "Strip all",
+            Policy::KeepFormatting => "Keep formatting",
+            Policy::KeepRuby => "Keep ruby",
+            Policy::KeepMedia => "Keep images and sound",
+            Policy::Passthrough => "Passthrough",
+        }
+    }
+
+    pub fn clean(self, html: &str) -> String {
+        let mut tags: HashSet<&str> = HashSet::new();
+        match self {
+            Policy::Passthrough => return html.to_owned(),
+            Policy::StripAll => {}
+            Policy::KeepFormatting => tags.extend(FORMATTING),
+            Policy::KeepRuby => tags.extend(FORMATTING.iter().chain(RUBY)),
+            Policy::KeepMedia => tags.extend(FORMATTING.iter().chain(RUBY).chain(&["img"])),
+        }
+
+        let mut builder = ammonia::Builder::empty();
+        builder
+            .tags(tags)
+            .clean_content_tags(HashSet::from(["script", "style"]))
+            .add_tag_attributes("span", &["style"])
+            .add_tag_attributes("font", &["color"]);
+        if self == Policy::KeepMedia {
+            builder
+                .add_tag_attributes("img", &["src", "alt"])
+                .url_relative(ammonia::UrlRelative::PassThrough)
+                .add_url_schemes(&["http", "https", "data"]);
+        }
+        let cleaned = builder.clean(html).to_string();
+        if self == Policy::KeepMedia {
+            return cleaned;
+        }
+        let sound = Regex::new(r"\[sound:[^\]]*\]").unwrap();
+        sound.replace_all(&cleaned, "").into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Field values as they appear in KanKen notes.
+    const SENTENCE_BACK: &str = r#"<ruby><rb>欠伸</rb><rt>あくび</rt></ruby>を<b>噛み殺す</b>。<br><span style="color: rgb(255, 0, 0);">眠</span>い"#;
+    const PICTURE: &str = r#"<img src="kamikorosu.jpg">"#;
+    const MEANING: &str =
+        r#"<div>to stifle (a yawn)</div><div><i>to bite to death</i>&nbsp;&amp; more</div>"#;
+    const AUDIO: &str = "[sound:kamikorosu.mp3]";
+    const UNSAFE: &str =
+        r#"<a href="x" onclick="y">a</a><script>alert(1)</script><b onmouseover="y">b</b>"#;
+
+    fn clean_all(policy: Policy) -> [String; 5] {
+        [SENTENCE_BACK, PICTURE, MEANING, AUDIO, UNSAFE].map(|s| policy.clean(s))
+    }
+
+    #[test]
+    fn test_strip_all() {
+        assert_eq!(
+            clean_all(Policy::StripAll),
+            [
+                "欠伸あくびを噛み殺す。眠い",
+                "",
+                "to stifle (a yawn)to bite to death&nbsp;&amp; more",
+                "",
+                "ab",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep_formatting() {
+        assert_eq!(
+            clean_all(Policy::KeepFormatting),
+            [
+                r#"欠伸あくびを<b>噛み殺す</b>。<br><span style="color: rgb(255, 0, 0);">眠</span>い"#,
+                "",
+                MEANING,
+                "",
+                "a<b>b</b>",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keep_ruby() {
+        assert_eq!(
+            clean_all(Policy::KeepRuby),
+            [SENTENCE_BACK, "", MEANING, "", "a<b>b</b>"]
+        );
+    }
+
+    #[test]
+    fn test_keep_media() {
+        assert_eq!(
+            clean_all(Policy::KeepMedia),
+            [SENTENCE_BACK, PICTURE, MEANING, AUDIO, "a<b>b</b>"]
+        );
+        assert_eq!(
+            Policy::KeepMedia.clean(r#"<img src="https://example.com/a.png" onerror="x">"#),
+            r#"<img src="https://example.com/a.png">"#
+        );
+    }
+
+    #[test]
+    fn test_passthrough() {
+        assert_eq!(
+            clean_all(Policy::Passthrough),
+            [SENTENCE_BACK, PICTURE, MEANING, AUDIO, UNSAFE]
+        );
+    }
+}
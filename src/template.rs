@@ -0,0 +1,297 @@
+use std::fmt;
+
+use crate::furigana;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateError {
+    Unclosed(usize),
+    UnknownFilter(String),
+    UnmatchedSection(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unclosed(pos) => write!(f, "unclosed {{{{ at {pos}"),
+            Self::UnknownFilter(name) => write!(f, "unknown filter `{name}`"),
+            Self::UnmatchedSection(name) => write!(f, "unmatched section `{name}`"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+pub const FILTERS: &[&str] = &[
+    "strip_html",
+    "nl2br",
+    "trim",
+    "furigana",
+    "kanji",
+    "kana",
+    "furigana_base",
+    "furigana_reading",
+];
+
+/// `{{Kanji|reading:Kana}}` writes the field as furigana, with the reading in
+/// the named field split across its kanji. See [`furigana::align`].
+pub const READING_FILTER: &str = "reading:";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Text(String),
+    Field {
+        name: String,
+        filters: Vec<String>,
+    },
+    /// `{{#Field}}...{{/Field}}` renders its body only when the field is not blank,
+    /// `{{^Field}}...{{/Field}}` only when it is.
+    Section {
+        name: String,
+        inverted: bool,
+        body: Vec<Node>,
+    },
+}
+
+/// A section being parsed: its name and whether it is inverted, and its body so far.
+type Frame = (Option<(String, bool)>, Vec<Node>);
+
+/// A field template such as `{{Kanji}}[{{Kana}}]` or
+/// `{{SentenceBack|strip_html}}{{#Picture}}<br />{{Picture}}{{/Picture}}`.
+///
+/// Filters may also be written before the field as in Anki, so `{{kana:Front}}`
+/// is `{{Front|kana}}`, and `{{text:kana:Front}}` is `{{Front|kana|text}}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+impl Template {
+    pub fn parse(src: &str) -> Result<Self, TemplateError> {
+        // the root is at the bottom, with no section name
+        let mut stack: Vec<Frame> = vec![(None, vec![])];
+        let mut rest = src;
+        while let Some(start) = rest.find("{{") {
+            let pos = src.len() - rest.len() + start;
+            let text = &rest[..start];
+            let after = &rest[start + 2..];
+            let end = after.find("}}").ok_or(TemplateError::Unclosed(pos))?;
+            let tag = after[..end].trim();
+            rest = &after[end + 2..];
+
+            let nodes = &mut stack.last_mut().unwrap().1;
+            if !text.is_empty() {
+                nodes.push(Node::Text(text.to_owned()));
+            }
+
+            if let Some(name) = tag.strip_prefix('#') {
+                stack.push((Some((name.trim().to_owned(), false)), vec![]));
+            } else if let Some(name) = tag.strip_prefix('^') {
+                stack.push((Some((name.trim().to_owned(), true)), vec![]));
+            } else if let Some(name) = tag.strip_prefix('/') {
+                let name = name.trim();
+                match stack.pop() {
+                    Some((Some((open, inverted)), body)) if open == name && !stack.is_empty() => {
+                        stack.last_mut().unwrap().1.push(Node::Section {
+                            name: open,
+                            inverted,
+                            body,
+                        });
+                    }
+                    _ => return Err(TemplateError::UnmatchedSection(name.to_owned())),
+                }
+            } else {
+                let mut parts = tag.split('|').map(str::trim);
+                let mut prefixed: Vec<&str> = parts.next().unwrap_or_default().split(':').collect();
+                let name = prefixed.pop().unwrap_or_default().trim().to_owned();
+                let filters = prefixed
+                    .into_iter()
+                    .rev()
+                    .map(str::trim)
+                    .chain(parts)
+                    .map(|f| {
+                        let reading_of = f.strip_prefix(READING_FILTER).map(str::trim);
+                        if FILTERS.contains(&f) || reading_of.is_some_and(|n| !n.is_empty()) {
+                            Ok(f.to_owned())
+                        } else {
+                            Err(TemplateError::UnknownFilter(f.to_owned()))
+                        }
+                    })
+                    .collect::<Result<_, _>>()?;
+                nodes.push(Node::Field { name, filters });
+            }
+        }
+
+        let (open, mut nodes) = stack.pop().unwrap();
+        if let Some((name, _)) = open {
+            return Err(TemplateError::UnmatchedSection(name));
+        }
+        if !rest.is_empty() {
+            nodes.push(Node::Text(rest.to_owned()));
+        }
+        Ok(Self { nodes })
+    }
+
+    /// Fields that `lookup` does not know render as empty.
+    pub fn render<'a>(&self, lookup: impl Fn(&str) -> Option<&'a str>) -> String {
+        let mut out = String::new();
+        render_nodes(&self.nodes, &lookup, &mut out);
+        out
+    }
+}
+
+pub fn render<'a>(
+    src: &str,
+    lookup: impl Fn(&str) -> Option<&'a str>,
+) -> Result<String, TemplateError> {
+    Ok(Template::parse(src)?.render(lookup))
+}
+
+fn render_nodes<'a>(nodes: &[Node], lookup: &impl Fn(&str) -> Option<&'a str>, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(t) => out.push_str(t),
+            Node::Field { name, filters } => {
+                let value = lookup(name).unwrap_or_default().to_owned();
+                let value = filters
+                    .iter()
+                    .fold(value, |v, filter| apply_filter(filter, &v, lookup));
+                out.push_str(&value);
+            }
+            Node::Section {
+                name,
+                inverted,
+                body,
+            } => {
+                let present = lookup(name).is_some_and(|v| !v.trim().is_empty());
+                if present != *inverted {
+                    render_nodes(body, lookup, out);
+                }
+            }
+        }
+    }
+}
+
+fn apply_filter<'a>(filter: &str, v: &str, lookup: &impl Fn(&str) -> Option<&'a str>) -> String {
+    if let Some(field) = filter.strip_prefix(READING_FILTER) {
+        let reading = lookup(field.trim()).unwrap_or_default().trim();
+        return furigana::format(&furigana::align(v.trim(), reading));
+    }
+    match filter {
+        "strip_html" => ammonia::Builder::empty().clean(v).to_string(),
+        "nl2br" => v.replace("\r\n", "\n").replace('\n', "<br />"),
+        "trim" => v.trim().to_owned(),
+        "furigana" => furigana::ruby(&furigana::parse(v)),
+        "kanji" | "furigana_base" => furigana::kanji(&furigana::parse(v)),
+        "kana" | "furigana_reading" => furigana::kana(&furigana::parse(v)),
+        _ => v.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn render_with(src: &str, fields: &[(&str, &str)]) -> String {
+        let fields: HashMap<_, _> = fields.iter().copied().collect();
+        render(src, |n| fields.get(n).copied()).unwrap()
+    }
+
+    #[test]
+    fn test_render_fields() {
+        assert_eq!(
+            render_with(
+                "{{Kanji}}[{{ Kana }}]",
+                &[("Kanji", "噛み殺す"), ("Kana", "かみころす")]
+            ),
+            "噛み殺す[かみころす]"
+        );
+        assert_eq!(render_with("a{{Missing}}b", &[]), "ab");
+        assert_eq!(render_with("no tags", &[]), "no tags");
+    }
+
+    #[test]
+    fn test_render_filters() {
+        let fields = [
+            ("Sentence", " 欠伸を<b>噛み殺す</b>\n "),
+            ("Front", "噛[か]み 殺[ころ]す"),
+        ];
+        assert_eq!(
+            render_with("{{Sentence|strip_html|trim|nl2br}}", &fields),
+            "欠伸を噛み殺す"
+        );
+        assert_eq!(
+            render_with("{{Sentence|trim|nl2br}}", &[("Sentence", "a\nb\r\nc")]),
+            "a<br />b<br />c"
+        );
+        assert_eq!(render_with("{{Front|furigana_base}}", &fields), "噛み殺す");
+        assert_eq!(
+            render_with("{{Front|furigana_reading}}", &fields),
+            "かみころす"
+        );
+        assert_eq!(render_with("{{kana:Front}}", &fields), "かみころす");
+        assert_eq!(render_with("{{ kanji: Front }}", &fields), "噛み殺す");
+        assert_eq!(
+            render_with("{{furigana:Front}}", &fields),
+            "<ruby><rb>噛</rb><rt>か</rt></ruby>み<ruby><rb>殺</rb><rt>ころ</rt></ruby>す"
+        );
+        assert_eq!(
+            render_with("{{trim:kana:Sentence}}", &[("Sentence", " 噛[か]む ")]),
+            "かむ"
+        );
+        let fields = [("Kanji", "噛み殺す"), ("Kana", "かみころす")];
+        assert_eq!(
+            render_with("{{Kanji|reading:Kana}}", &fields),
+            "噛[か]み 殺[ころ]す"
+        );
+        assert_eq!(
+            render_with("{{Kanji|reading: Missing}}", &fields),
+            "噛み殺す"
+        );
+    }
+
+    #[test]
+    fn test_render_sections() {
+        let src = "{{Sentence}}{{#Picture}}<br />{{Picture}}{{/Picture}}{{^Picture}}!{{/Picture}}";
+        assert_eq!(
+            render_with(
+                src,
+                &[("Sentence", "s"), ("Picture", "<img src=\"p.jpg\">")]
+            ),
+            "s<br /><img src=\"p.jpg\">"
+        );
+        assert_eq!(
+            render_with(src, &[("Sentence", "s"), ("Picture", " ")]),
+            "s!"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Template::parse("ab{{Kanji"),
+            Err(TemplateError::Unclosed(2))
+        );
+        assert_eq!(
+            Template::parse("{{Kanji|shout}}"),
+            Err(TemplateError::UnknownFilter("shout".into()))
+        );
+        assert_eq!(
+            Template::parse("{{Kanji|reading:}}"),
+            Err(TemplateError::UnknownFilter("reading:".into()))
+        );
+        assert_eq!(
+            Template::parse("{{#A}}x"),
+            Err(TemplateError::UnmatchedSection("A".into()))
+        );
+        assert_eq!(
+            Template::parse("{{#A}}x{{/B}}"),
+            Err(TemplateError::UnmatchedSection("B".into()))
+        );
+        assert_eq!(
+            Template::parse("x{{/B}}"),
+            Err(TemplateError::UnmatchedSection("B".into()))
+        );
+    }
+}
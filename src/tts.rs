@@ -0,0 +1,154 @@
+use std::{
+    env, fs,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use base64::Engine;
+
+use crate::{
+    ankiconnect::{Client, MediaSource},
+    card::GuiAddCardsFields,
+    config::Tts,
+    error::AnkiError,
+    media::MEDIA_PREFIX,
+};
+
+/// Runs the command to voice `text`, returning the audio it wrote.
+pub fn synthesize(tts: &Tts, text: &str) -> Result<Vec<u8>, AnkiError> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let output = env::temp_dir().join(format!(
+        "anki-copy-card-tts-{}-{}.{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed),
+        tts.extension
+    ));
+    let res = run(tts, text, &output);
+    let audio = res.and_then(|()| fs::read(&output).map_err(|e| AnkiError::Tts(e.to_string())));
+    _ = fs::remove_file(&output);
+    audio
+}
+
+fn run(tts: &Tts, text: &str, output: &Path) -> Result<(), AnkiError> {
+    let output = output.to_string_lossy();
+    let args: Vec<String> = tts
+        .args
+        .iter()
+        .filter(|a| !a.is_empty())
+        .map(|a| a.replace("{text}", text).replace("{output}", &output))
+        .collect();
+    let stdin = !tts.args.iter().any(|a| a.contains("{text}"));
+
+    let mut child = Command::new(&tts.program)
+        .args(&args)
+        .stdin(if stdin { Stdio::piped() } else { Stdio::null() })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| AnkiError::Tts(format!("cannot run {}: {e}", tts.program)))?;
+    if let Some(mut input) = child.stdin.take() {
+        // a command that fails early closes its input, which its exit status reports better
+        _ = input.write_all(text.as_bytes());
+    }
+    let out = child
+        .wait_with_output()
+        .map_err(|e| AnkiError::Tts(e.to_string()))?;
+    if !out.status.success() {
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        return Err(AnkiError::Tts(format!(
+            "{} exited with {}: {}",
+            tts.program,
+            out.status,
+            stderr.trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Names the file after the text, so voicing the same text again reuses it.
+pub fn media_filename(tts: &Tts, text: &str) -> String {
+    let name: String = text
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '[' | ']' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect();
+    format!("{MEDIA_PREFIX}tts-{name}.{}", tts.extension)
+}
+
+/// Voices the audio guide into the audio field when that is blank.
+pub fn fill_audio(
+    client: &Client,
+    tts: &Tts,
+    audio_guide_field: &str,
+    card: &mut GuiAddCardsFields,
+) -> Result<(), AnkiError> {
+    let text = card.get(audio_guide_field).trim().to_owned();
+    if text.is_empty() || !card.get(&tts.audio_field).trim().is_empty() {
+        return Ok(());
+    }
+    let audio = synthesize(tts, &text)?;
+    let data = base64::engine::general_purpose::STANDARD.encode(audio);
+    let stored = client.store_media_file(&media_filename(tts, &text), &MediaSource::Data(data))?;
+    card.set(&tts.audio_field, format!("[sound:{stored}]"));
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn fake(args: &[&str]) -> Tts {
+        Tts {
+            enabled: true,
+            program: "sh".into(),
+            args: args.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_synthesize() {
+        let tts = fake(&[
+            "-c",
+            r#"printf 'RIFF%s' "$1" > "$2""#,
+            "sh",
+            "{text}",
+            "{output}",
+        ]);
+        assert_eq!(
+            synthesize(&tts, "噛み殺す").unwrap(),
+            "RIFF噛み殺す".as_bytes()
+        );
+
+        let tts = fake(&["-c", r#"cat > "$1""#, "sh", "{output}"]);
+        assert_eq!(synthesize(&tts, "かむ").unwrap(), "かむ".as_bytes());
+    }
+
+    #[test]
+    fn test_synthesize_failure() {
+        let tts = fake(&["-c", "echo no voice >&2; exit 3"]);
+        let Err(AnkiError::Tts(e)) = synthesize(&tts, "噛む") else {
+            panic!();
+        };
+        assert!(e.contains("no voice"), "{e}");
+
+        let tts = Tts {
+            program: "/nonexistent/tts".into(),
+            ..Default::default()
+        };
+        assert!(matches!(synthesize(&tts, "噛む"), Err(AnkiError::Tts(_))));
+    }
+
+    #[test]
+    fn test_media_filename() {
+        assert_eq!(
+            media_filename(&Tts::default(), "a/b c[d]"),
+            "anki-copy-card-tts-a_b_c_d_.wav"
+        );
+    }
+}
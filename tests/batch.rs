@@ -0,0 +1,134 @@
+mod common;
+
+use anki_copy_card_egui::{
+    batch::{self, RowStatus, Table},
+    card::DEFAULT_TARGET_FIELDS,
+    config::Settings,
+};
+use common::{MockAnki, Reply};
+
+fn rows(text: &str) -> Vec<batch::BatchRow> {
+    let fields = DEFAULT_TARGET_FIELDS.map(String::from).to_vec();
+    let table = Table::parse(text, b'\t').unwrap();
+    let mapping = batch::default_mapping(&table.headers, &fields);
+    batch::build_rows(&table, &mapping, &Settings::default().target, &fields)
+}
+
+#[test]
+fn test_batch_check_and_submit() {
+    let mock = MockAnki::start();
+    mock.result("canAddNotes", serde_json::json!([true, false, true]))
+        .result(
+            "multi",
+            serde_json::json!([
+                { "result": 101, "error": null },
+                { "result": null, "error": "cannot create note because it is a duplicate" },
+            ]),
+        )
+        .result(
+            "multi",
+            serde_json::json!([
+                { "result": 102, "error": null },
+                { "result": 103, "error": null },
+            ]),
+        );
+
+    let settings = Settings::default();
+    let mut rows = rows("Front\tBack\n噛[か]む\tto bite\n殺[ころ]す\tto kill\n\tx\n噛[か]む\tagain\n欠伸[あくび]\tyawn\n");
+    batch::check_duplicates(&mock.client(), &settings, &mut rows).unwrap();
+
+    let statuses: Vec<_> = rows.iter().map(|r| r.status.clone()).collect();
+    assert_eq!(
+        statuses,
+        vec![
+            RowStatus::Ready,
+            RowStatus::Duplicate,
+            RowStatus::Invalid("Front is empty".into()),
+            RowStatus::DuplicateInFile(0),
+            RowStatus::Ready,
+        ]
+    );
+    let checked = mock.request("canAddNotes").unwrap();
+    let notes = checked["params"]["notes"].as_array().unwrap();
+    assert_eq!(notes.len(), 3);
+    assert_eq!(notes[0]["fields"]["AudioGuide"], "噛む");
+    assert_eq!(notes[0]["deckName"], "Immersion");
+
+    batch::submit(&mock.client(), &settings, &mut rows);
+    assert_eq!(rows[0].status, RowStatus::Added(101));
+    assert_eq!(
+        rows[4].status,
+        RowStatus::Failed("AnkiConnect error: cannot create note because it is a duplicate".into())
+    );
+    assert_eq!(rows[1].status, RowStatus::Duplicate);
+    let submitted = mock.request("multi").unwrap();
+    let actions = submitted["params"]["actions"].as_array().unwrap();
+    assert_eq!(actions.len(), 2);
+    assert_eq!(actions[1]["action"], "addNote");
+    assert_eq!(actions[1]["version"], 6);
+    assert_eq!(
+        actions[1]["params"]["note"]["fields"]["Front"],
+        "欠伸[あくび]"
+    );
+
+    // the failed row can be retried, and a duplicate added on purpose
+    rows[1].include = true;
+    rows[4].include = true;
+    batch::submit(&mock.client(), &settings, &mut rows);
+    assert_eq!(rows[1].status, RowStatus::Added(102));
+    assert_eq!(rows[4].status, RowStatus::Added(103));
+    let last = mock.requests().pop().unwrap();
+    let actions = &last["params"]["actions"];
+    assert_eq!(
+        actions[0]["params"]["note"]["options"]["allowDuplicate"],
+        true
+    );
+    assert_eq!(
+        actions[1]["params"]["note"]["options"]["allowDuplicate"],
+        false
+    );
+}
+
+#[test]
+fn test_batch_check_ignores_allow_duplicate() {
+    let mock = MockAnki::start();
+    mock.result("canAddNotes", serde_json::json!([false]))
+        .result(
+            "multi",
+            serde_json::json!([{ "result": 101, "error": null }]),
+        );
+
+    let mut settings = Settings::default();
+    settings.add.allow_duplicate = true;
+    let mut rows = rows("Front\n噛[か]む\n");
+    batch::check_duplicates(&mock.client(), &settings, &mut rows).unwrap();
+    let checked = mock.request("canAddNotes").unwrap();
+    assert_eq!(
+        checked["params"]["notes"][0]["options"]["allowDuplicate"],
+        false
+    );
+    assert_eq!(rows[0].status, RowStatus::Duplicate);
+
+    rows[0].include = true;
+    batch::submit(&mock.client(), &settings, &mut rows);
+    assert_eq!(rows[0].status, RowStatus::Added(101));
+    let submitted = mock.request("multi").unwrap();
+    assert_eq!(
+        submitted["params"]["actions"][0]["params"]["note"]["options"]["allowDuplicate"],
+        true
+    );
+}
+
+#[test]
+fn test_batch_submit_rejected() {
+    let mock = MockAnki::start();
+    mock.on("multi", Reply::Error("collection is not open".into()));
+
+    let mut rows = rows("Front\n噛[か]む\n");
+    batch::submit(&mock.client(), &Settings::default(), &mut rows);
+    let RowStatus::Failed(e) = &rows[0].status else {
+        panic!();
+    };
+    assert!(e.contains("collection is not open"));
+    assert!(!rows[0].include);
+}
@@ -0,0 +1,180 @@
+//! A fake AnkiConnect server listening on an ephemeral localhost port.
+
+// not every test crate uses every helper
+#![allow(dead_code)]
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anki_copy_card_egui::{ankiconnect::Client, config::Connection};
+
+#[derive(Debug, Clone)]
+pub enum Reply {
+    /// Sent back as `{"result": ..., "error": null}`.
+    Result(serde_json::Value),
+    /// Sent back as `{"result": null, "error": ...}`.
+    Error(String),
+    /// Sent back as is, to test malformed responses.
+    Raw(String),
+    Status(u16),
+}
+
+#[derive(Default)]
+struct State {
+    requests: Vec<serde_json::Value>,
+    replies: HashMap<String, VecDeque<Reply>>,
+}
+
+pub struct MockAnki {
+    pub url: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockAnki {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let s = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    return;
+                };
+                let s = s.clone();
+                thread::spawn(move || serve(stream, &s));
+            }
+        });
+
+        Self { url, state }
+    }
+
+    /// Replies to the next request for `action`. Replies queue up in order, and
+    /// the last one is repeated.
+    pub fn on(&self, action: &str, reply: Reply) -> &Self {
+        self.state
+            .lock()
+            .unwrap()
+            .replies
+            .entry(action.to_owned())
+            .or_default()
+            .push_back(reply);
+        self
+    }
+
+    pub fn result(&self, action: &str, result: serde_json::Value) -> &Self {
+        self.on(action, Reply::Result(result))
+    }
+
+    pub fn requests(&self) -> Vec<serde_json::Value> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn actions(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .map(|r| r["action"].as_str().unwrap_or_default().to_owned())
+            .collect()
+    }
+
+    pub fn request(&self, action: &str) -> Option<serde_json::Value> {
+        self.requests().into_iter().find(|r| r["action"] == action)
+    }
+
+    pub fn connection(&self) -> Connection {
+        Connection {
+            url: self.url.clone(),
+            api_key: Some("secret".into()),
+            version: 6,
+        }
+    }
+
+    pub fn client(&self) -> Client {
+        Client::new(self.connection())
+    }
+}
+
+fn serve(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    loop {
+        let mut content_length = 0;
+        let mut line = String::new();
+        // request line, then headers until a blank line
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+
+        let request: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+        let action = request["action"].as_str().unwrap_or_default().to_owned();
+        let reply = {
+            let mut state = state.lock().unwrap();
+            state.requests.push(request);
+            match state.replies.get_mut(&action) {
+                Some(q) if q.len() > 1 => q.pop_front().unwrap(),
+                Some(q) if !q.is_empty() => q[0].clone(),
+                _ => Reply::Error("unsupported action".into()),
+            }
+        };
+
+        let (status, body) = match reply {
+            Reply::Result(r) => (
+                200,
+                serde_json::json!({ "result": r, "error": null }).to_string(),
+            ),
+            Reply::Error(e) => (
+                200,
+                serde_json::json!({ "result": null, "error": e }).to_string(),
+            ),
+            Reply::Raw(raw) => (200, raw),
+            Reply::Status(code) => (code, String::new()),
+        };
+        let response = format!(
+            "HTTP/1.1 {status} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        if stream.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+pub fn kanken_card() -> serde_json::Value {
+    serde_json::json!({
+        "deckName": "KanKen Deck",
+        "modelName": "KanKen",
+        "fields": {
+            "Kanji": { "value": "噛み殺す", "order": 0 },
+            "Kana": { "value": "かみころす", "order": 1 },
+            "SentenceBack": { "value": "欠伸を<b>噛み殺す</b>", "order": 2 },
+            "Picture": { "value": "", "order": 3 },
+            "KankenAudio": { "value": "[sound:kamikorosu.mp3]", "order": 4 },
+            "Meaning": { "value": "to stifle a yawn", "order": 5 },
+        },
+    })
+}
@@ -0,0 +1,285 @@
+mod common;
+
+use std::{collections::BTreeMap, net::TcpListener};
+
+use anki_copy_card_egui::{
+    ankiconnect::Client,
+    card::DEFAULT_TARGET_FIELDS,
+    config::{AddMode, Connection, Settings},
+    error::AnkiError,
+    fire::{self, FireOutcome},
+};
+use common::{kanken_card, MockAnki, Reply};
+
+fn fields() -> Vec<String> {
+    DEFAULT_TARGET_FIELDS.map(String::from).to_vec()
+}
+
+fn fire(mock: &MockAnki, settings: &Settings) -> Result<FireOutcome, AnkiError> {
+    fire::fire_card(&mock.client(), settings, &fields(), None, BTreeMap::new())
+}
+
+#[test]
+fn test_fire_through_add_dialog() {
+    let mock = MockAnki::start();
+    mock.result("guiCurrentCard", kanken_card())
+        .result("findNotes", serde_json::json!([]))
+        .result("retrieveMediaFile", serde_json::json!("SUQz"))
+        .result(
+            "storeMediaFile",
+            serde_json::json!("anki-copy-card-kamikorosu.mp3"),
+        )
+        .result("guiAddCards", serde_json::json!(1496198395707i64));
+
+    let Ok(FireOutcome::Added(fired)) = fire(&mock, &Settings::default()) else {
+        panic!();
+    };
+    assert_eq!(fired.note_id, Some(1496198395707));
+    assert_eq!(fired.card.get("Front"), "噛[か]み 殺[ころ]す");
+    assert_eq!(
+        mock.actions(),
+        vec![
+            "guiCurrentCard",
+            "findNotes",
+            "retrieveMediaFile",
+            "storeMediaFile",
+            "guiAddCards"
+        ]
+    );
+    assert_eq!(
+        mock.request("retrieveMediaFile").unwrap()["params"],
+        serde_json::json!({ "filename": "kamikorosu.mp3" })
+    );
+    assert_eq!(
+        mock.request("storeMediaFile").unwrap()["params"],
+        serde_json::json!({ "filename": "anki-copy-card-kamikorosu.mp3", "data": "SUQz" })
+    );
+
+    let req = mock.request("guiAddCards").unwrap();
+    assert_eq!(req["key"], "secret");
+    assert_eq!(req["version"], 6);
+    assert_eq!(
+        req["params"]["note"],
+        serde_json::json!({
+            "deckName": "Immersion",
+            "modelName": "Immersion",
+            "fields": {
+                "Front": "噛[か]み 殺[ころ]す",
+                "Back": "to stifle a yawn",
+                "Back Paragraph": "欠伸を<b>噛み殺す</b>",
+                "AudioGuide": "噛み殺す",
+                "Audio": "[sound:anki-copy-card-kamikorosu.mp3]",
+            },
+            "tags": ["Immersion", "from::KanKen_Deck"],
+        })
+    );
+    assert_eq!(
+        mock.request("findNotes").unwrap()["params"]["query"],
+        r#""deck:Immersion" "Front:噛[か]み 殺[ころ]す""#
+    );
+}
+
+#[test]
+fn test_fire_silent_add() {
+    let mock = MockAnki::start();
+    mock.result("guiCurrentCard", kanken_card())
+        .result("findNotes", serde_json::json!([]))
+        .result("addNote", serde_json::json!(42));
+
+    let mut settings = Settings::default();
+    settings.add.mode = AddMode::Silent;
+    settings.add.copy_media = false;
+    let Ok(FireOutcome::Added(fired)) = fire(&mock, &settings) else {
+        panic!();
+    };
+    assert_eq!(fired.note_id, Some(42));
+    assert_eq!(
+        mock.request("addNote").unwrap()["params"]["note"]["options"]["allowDuplicate"],
+        false
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_fire_voices_blank_audio() {
+    let mock = MockAnki::start();
+    let mut card = kanken_card();
+    card["fields"]["KankenAudio"]["value"] = "".into();
+    mock.result("guiCurrentCard", card)
+        .result("findNotes", serde_json::json!([]))
+        .result(
+            "storeMediaFile",
+            serde_json::json!("anki-copy-card-tts-噛み殺す.wav"),
+        )
+        .result("addNote", serde_json::json!(42));
+
+    let mut settings = Settings::default();
+    settings.add.mode = AddMode::Silent;
+    settings.tts.enabled = true;
+    // a fake voice that writes the text it was given
+    settings.tts.program = "sh".into();
+    settings.tts.args = [
+        "-c",
+        r#"printf '%s' "$1" > "$2""#,
+        "sh",
+        "{text}",
+        "{output}",
+    ]
+    .map(String::from)
+    .to_vec();
+
+    let Ok(FireOutcome::Added(fired)) = fire(&mock, &settings) else {
+        panic!();
+    };
+    assert_eq!(
+        fired.card.get("Audio"),
+        "[sound:anki-copy-card-tts-噛み殺す.wav]"
+    );
+    assert_eq!(
+        mock.request("storeMediaFile").unwrap()["params"],
+        serde_json::json!({
+            "filename": "anki-copy-card-tts-噛み殺す.wav",
+            // 噛み殺す in base64
+            "data": "5Zmb44G/5q6644GZ",
+        })
+    );
+    assert_eq!(
+        mock.request("addNote").unwrap()["params"]["note"]["fields"]["Audio"],
+        "[sound:anki-copy-card-tts-噛み殺す.wav]"
+    );
+}
+
+#[test]
+fn test_fire_finds_duplicates() {
+    let mock = MockAnki::start();
+    mock.result("guiCurrentCard", kanken_card())
+        .result("findNotes", serde_json::json!([7]))
+        .result(
+            "notesInfo",
+            serde_json::json!([{
+                "noteId": 7,
+                "modelName": "Immersion",
+                "tags": [],
+                "fields": { "Front": { "value": "噛[か]み 殺[ころ]す", "order": 0 } },
+            }]),
+        )
+        .result("updateNoteFields", serde_json::Value::Null);
+
+    let Ok(FireOutcome::Duplicates(fired, existing)) = fire(&mock, &Settings::default()) else {
+        panic!();
+    };
+    assert_eq!(existing.len(), 1);
+    assert_eq!(existing[0].note_id, 7);
+    assert!(!mock.actions().contains(&"guiAddCards".to_owned()));
+
+    // missing media is left as it is
+    mock.result("retrieveMediaFile", serde_json::json!(false));
+    let updated = fire::update_card(&mock.client(), &Settings::default(), 7, fired).unwrap();
+    assert_eq!(updated.note_id, Some(7));
+    let req = mock.request("updateNoteFields").unwrap();
+    assert_eq!(req["params"]["note"]["id"], 7);
+    assert_eq!(req["params"]["note"]["fields"]["Back"], "to stifle a yawn");
+    assert_eq!(
+        req["params"]["note"]["fields"]["Audio"],
+        "[sound:kamikorosu.mp3]"
+    );
+    assert!(mock.request("storeMediaFile").is_none());
+}
+
+#[test]
+fn test_fire_no_current_card() {
+    let mock = MockAnki::start();
+    mock.result("guiCurrentCard", serde_json::Value::Null);
+    assert_eq!(
+        fire(&mock, &Settings::default()).unwrap_err(),
+        AnkiError::NoCurrentCard
+    );
+}
+
+#[test]
+fn test_fire_error_envelope() {
+    let mock = MockAnki::start();
+    mock.result("guiCurrentCard", kanken_card())
+        .result("findNotes", serde_json::json!([]))
+        .result("retrieveMediaFile", serde_json::json!(false))
+        .on(
+            "guiAddCards",
+            Reply::Error("deck was not found: Immersion".into()),
+        );
+    assert_eq!(
+        fire(&mock, &Settings::default()).unwrap_err(),
+        AnkiError::Anki("deck was not found: Immersion".into())
+    );
+}
+
+#[test]
+fn test_fire_malformed_json() {
+    let mock = MockAnki::start();
+    mock.on("guiCurrentCard", Reply::Raw("{\"result\": ".into()));
+    assert!(matches!(
+        fire(&mock, &Settings::default()).unwrap_err(),
+        AnkiError::Deserialize {
+            missing_field: None,
+            ..
+        }
+    ));
+}
+
+#[test]
+fn test_fire_missing_field() {
+    let mock = MockAnki::start();
+    let mut card = kanken_card();
+    card.as_object_mut().unwrap().remove("modelName");
+    mock.result("guiCurrentCard", card);
+    let Err(AnkiError::Deserialize { missing_field, .. }) = fire(&mock, &Settings::default())
+    else {
+        panic!();
+    };
+    assert_eq!(missing_field.as_deref(), Some("modelName"));
+}
+
+#[test]
+fn test_fire_http_error() {
+    let mock = MockAnki::start();
+    mock.on("guiCurrentCard", Reply::Status(500));
+    assert_eq!(
+        fire(&mock, &Settings::default()).unwrap_err(),
+        AnkiError::Http(500)
+    );
+}
+
+#[test]
+fn test_fire_connection_refused() {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let client = Client::new(Connection {
+        url: format!("http://{addr}"),
+        ..Default::default()
+    });
+    let err = fire::fire_card(
+        &client,
+        &Settings::default(),
+        &fields(),
+        None,
+        BTreeMap::new(),
+    )
+    .unwrap_err();
+    assert!(matches!(err, AnkiError::Connection(_)), "{err:?}");
+}
+
+#[test]
+fn test_multi() {
+    let mock = MockAnki::start();
+    mock.result(
+        "multi",
+        serde_json::json!([
+            { "result": ["Default"], "error": null },
+            { "result": null, "error": "unsupported action" },
+        ]),
+    );
+    let res = mock.client().multi(&[]).unwrap();
+    assert_eq!(res[0], Ok(Some(serde_json::json!(["Default"]))));
+    assert_eq!(res[1], Err(AnkiError::Anki("unsupported action".into())));
+}